@@ -0,0 +1,468 @@
+use crate::filesystem::RealFs;
+use crate::rules::Rule;
+use crate::safety::check_protected_dir;
+use crate::{DirConfig, validate_path_safety_with};
+use serde::Deserialize;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in a config file, with the byte range it refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Option<Range<usize>>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+        }
+    }
+
+    pub fn warning(message: String, span: Option<Range<usize>>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message,
+            span,
+        }
+    }
+
+    /// Diagnostic for a TOML syntax or schema error.
+    pub fn from_toml(err: &toml::de::Error) -> Self {
+        Diagnostic::error(err.message().to_string(), err.span())
+    }
+
+    /// Render as `severity: file:line:col: message`, followed by the offending source line.
+    pub fn render(&self, source: &str, file: &Path) -> String {
+        let location = self.location(source, file);
+        let Some(span) = &self.span else {
+            return format!("{}: {}: {}", self.severity, location, self.message);
+        };
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let column = source[line_start..start].chars().count() + 1;
+
+        format!(
+            "{}: {}: {}\n    | {}\n    | {}^",
+            self.severity,
+            location,
+            self.message,
+            &source[line_start..line_end],
+            " ".repeat(column - 1),
+        )
+    }
+
+    /// `file:line:col` of the start of the span, or just `file` without one.
+    pub fn location(&self, source: &str, file: &Path) -> String {
+        let Some(span) = &self.span else {
+            return file.display().to_string();
+        };
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count() + 1;
+        format!("{}:{}:{}", file.display(), line, column)
+    }
+}
+
+/// A diagnostic in one of the files a config was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Located {
+    pub file: PathBuf,
+    /// Text of `file`, to show the offending line.
+    pub source: String,
+    pub diagnostic: Diagnostic,
+}
+
+impl Located {
+    /// The diagnostic with the line it points at, as printed by `duansheli check`.
+    pub fn render(&self) -> String {
+        self.diagnostic.render(&self.source, &self.file)
+    }
+}
+
+/// Spans of the keys checked below, parsed separately from the real config.
+#[derive(Deserialize, Default)]
+struct ConfigSpans {
+    #[serde(default)]
+    dirs: Vec<Spanned<DirSpans>>,
+}
+
+#[derive(Deserialize, Default)]
+struct DirSpans {
+    path: Option<Spanned<toml::Value>>,
+    time_to_deletion_hours: Option<Spanned<toml::Value>>,
+    max_fraction: Option<Spanned<toml::Value>>,
+    #[serde(default)]
+    rules: Vec<Spanned<toml::Value>>,
+}
+
+impl DirSpans {
+    fn of(field: &Option<Spanned<toml::Value>>) -> Option<Range<usize>> {
+        field.as_ref().map(Spanned::span)
+    }
+}
+
+/// Semantic checks on parsed directory configs.
+///
+/// Each directory comes with the index in `sources` of the file it was
+/// written in and its position in that file's `dirs`, which locate its spans.
+pub fn check_dirs(
+    sources: &[(PathBuf, String)],
+    dirs: &[(DirConfig, usize, usize)],
+) -> Vec<Located> {
+    let spans: Vec<ConfigSpans> = sources
+        .iter()
+        .map(|(_, text)| toml::from_str(text).unwrap_or_default())
+        .collect();
+    let mut seen = Vec::new();
+    let mut located = Vec::new();
+    for (dir, source, position) in dirs {
+        let (file, text) = &sources[*source];
+        let dir_spans = spans[*source].dirs.get(*position);
+        located.extend(
+            check_dir(dir, dir_spans, &mut seen)
+                .into_iter()
+                .map(|diagnostic| Located {
+                    file: file.clone(),
+                    source: text.clone(),
+                    diagnostic,
+                }),
+        );
+    }
+    located
+}
+
+/// Checks on one directory; `seen` holds the directories checked before it,
+/// resolved and as written.
+fn check_dir(
+    dir: &DirConfig,
+    dir_spans: Option<&Spanned<DirSpans>>,
+    seen: &mut Vec<(PathBuf, PathBuf)>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let table_span = dir_spans.map(Spanned::span);
+    let field = |f: fn(&DirSpans) -> &Option<Spanned<toml::Value>>| {
+        dir_spans
+            .and_then(|d| DirSpans::of(f(d.get_ref())))
+            .or(table_span.clone())
+    };
+    let path_span = field(|d| &d.path);
+
+    let mut dir = dir.clone();
+    if let Err(e) = dir.expand_path() {
+        diagnostics.push(Diagnostic::error(e.to_string(), path_span));
+        return diagnostics;
+    }
+
+    if !dir.path.is_dir() {
+        diagnostics.push(Diagnostic::error(
+            format!(
+                "{} does not exist or is not a directory",
+                dir.path.display()
+            ),
+            path_span.clone(),
+        ));
+    }
+    let safety = validate_path_safety_with(&dir.path, &dir.protected_paths).and_then(|()| {
+        if dir.path.is_dir() {
+            check_protected_dir(&RealFs, &dir.path)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(e) = safety {
+        diagnostics.push(Diagnostic::error(e.to_string(), path_span.clone()));
+    }
+
+    let resolved = std::fs::canonicalize(&dir.path).unwrap_or_else(|_| dir.path.clone());
+    for (other, other_raw) in seen.iter() {
+        if *other == resolved {
+            diagnostics.push(Diagnostic::error(
+                format!("{} is listed more than once", dir.path.display()),
+                path_span.clone(),
+            ));
+        } else if resolved.starts_with(other) || other.starts_with(&resolved) {
+            diagnostics.push(Diagnostic::error(
+                format!(
+                    "{} and {} are nested; one would archive the other's contents",
+                    dir.path.display(),
+                    other_raw.display()
+                ),
+                path_span.clone(),
+            ));
+        }
+    }
+    seen.push((resolved, dir.path.clone()));
+
+    diagnostics.extend(check_thresholds(
+        dir.time_to_archive_hours,
+        dir.time_to_deletion_hours,
+        field(|d| &d.time_to_deletion_hours),
+    ));
+
+    if dir.max_fraction.is_some_and(|f| !(f > 0.0 && f <= 1.0)) {
+        diagnostics.push(Diagnostic::error(
+            "max_fraction must be greater than 0 and at most 1".to_string(),
+            field(|d| &d.max_fraction),
+        ));
+    }
+
+    for (rule_index, rule) in dir.rules.iter().enumerate() {
+        let rule_span = dir_spans
+            .and_then(|d| d.get_ref().rules.get(rule_index))
+            .map(Spanned::span)
+            .or(table_span.clone());
+        let is_last = rule_index + 1 == dir.rules.len();
+        diagnostics.extend(check_rule(rule, is_last, rule_span));
+    }
+
+    diagnostics
+}
+
+fn check_thresholds(
+    archive_hours: u64,
+    delete_hours: u64,
+    span: Option<Range<usize>>,
+) -> Option<Diagnostic> {
+    if delete_hours < archive_hours {
+        Some(Diagnostic::error(
+            format!(
+                "time_to_deletion_hours ({delete_hours}) is less than time_to_archive_hours ({archive_hours}); \
+                 entries would be deleted without ever being archived"
+            ),
+            span,
+        ))
+    } else if delete_hours == archive_hours {
+        Some(Diagnostic::warning(
+            format!(
+                "time_to_deletion_hours equals time_to_archive_hours ({delete_hours}); entries are never archived"
+            ),
+            span,
+        ))
+    } else {
+        None
+    }
+}
+
+fn check_rule(rule: &Rule, is_last: bool, span: Option<Range<usize>>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    if let (Some(min), Some(max)) = (rule.min_size, rule.max_size)
+        && min > max
+    {
+        diagnostics.push(Diagnostic::error(
+            format!("rule min_size ({min}) is larger than max_size ({max}); it can never match"),
+            span.clone(),
+        ));
+    }
+    if let (Some(min), Some(max)) = (rule.min_age_hours, rule.max_age_hours)
+        && min > max
+    {
+        diagnostics.push(Diagnostic::error(
+            format!("rule min_age_hours ({min}) is larger than max_age_hours ({max}); it can never match"),
+            span.clone(),
+        ));
+    }
+    if let (Some(archive), Some(delete)) = (rule.time_to_archive_hours, rule.time_to_deletion_hours)
+    {
+        diagnostics.extend(check_thresholds(archive, delete, span.clone()));
+    }
+    if rule.move_to.is_some() && rule.action.is_some() {
+        diagnostics.push(Diagnostic::warning(
+            "rule sets both move_to and action; move_to takes precedence".to_string(),
+            span.clone(),
+        ));
+    }
+    if !rule.has_conditions() && !is_last {
+        diagnostics.push(Diagnostic::warning(
+            "rule has no conditions and matches everything; later rules never apply".to_string(),
+            span,
+        ));
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn check(source: &str) -> Vec<Located> {
+        #[derive(Deserialize)]
+        struct Config {
+            dirs: Vec<DirConfig>,
+        }
+        let config: Config = toml::from_str(source).unwrap();
+        let dirs: Vec<_> = config
+            .dirs
+            .into_iter()
+            .enumerate()
+            .map(|(position, dir)| (dir, 0, position))
+            .collect();
+        check_dirs(&[(PathBuf::from("c.toml"), source.to_string())], &dirs)
+    }
+
+    #[test]
+    fn test_valid_config_has_no_diagnostics() {
+        let tmp = TempDir::new().unwrap();
+        let source = format!(
+            "[[dirs]]\npath = \"{}\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\n",
+            tmp.path().display()
+        );
+        assert_eq!(check(&source), vec![]);
+    }
+
+    #[test]
+    fn test_deletion_before_archive_points_at_key() {
+        let tmp = TempDir::new().unwrap();
+        let source = format!(
+            "[[dirs]]\npath = \"{}\"\ntime_to_archive_hours = 24\ntime_to_deletion_hours = 2\n",
+            tmp.path().display()
+        );
+        let diagnostics = check(&source);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].diagnostic.severity, Severity::Error);
+        let rendered = diagnostics[0].render();
+        assert!(
+            rendered.starts_with("error: c.toml:4:26: time_to_deletion_hours (2)"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_nested_and_missing_paths() {
+        let tmp = TempDir::new().unwrap();
+        let nested = tmp.path().join("inner");
+        std::fs::create_dir(&nested).unwrap();
+        let dir = |p: &Path| {
+            format!(
+                "[[dirs]]\npath = \"{}\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\n",
+                p.display()
+            )
+        };
+        let source = [
+            dir(tmp.path()),
+            dir(tmp.path()),
+            dir(&nested),
+            dir(&tmp.path().join("missing")),
+        ]
+        .concat();
+
+        let messages: Vec<_> = check(&source)
+            .into_iter()
+            .map(|d| d.diagnostic.message)
+            .collect();
+
+        assert!(
+            messages.iter().any(|m| m.contains("listed more than once")),
+            "{messages:?}"
+        );
+        assert!(
+            messages.iter().any(|m| m.contains("are nested")),
+            "{messages:?}"
+        );
+        assert!(
+            messages.iter().any(|m| m.contains("does not exist")),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn test_dangerous_path_is_an_error() {
+        let source =
+            "[[dirs]]\npath = \"/\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\n";
+        assert!(
+            check(source)
+                .iter()
+                .any(|d| d.diagnostic.message.contains("dangerous path"))
+        );
+    }
+
+    #[test]
+    fn test_rule_diagnostics() {
+        let tmp = TempDir::new().unwrap();
+        let source = format!(
+            "[[dirs]]\npath = \"{}\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\n\n\
+             [[dirs.rules]]\naction = \"keep\"\n\n\
+             [[dirs.rules]]\nmin_size = 100\nmax_size = 10\n",
+            tmp.path().display()
+        );
+        let diagnostics = check(&source);
+
+        assert_eq!(diagnostics.len(), 2, "{diagnostics:?}");
+        assert_eq!(diagnostics[0].diagnostic.severity, Severity::Warning);
+        assert!(diagnostics[0].render().contains("c.toml:6:"));
+        assert_eq!(diagnostics[1].diagnostic.severity, Severity::Error);
+        assert!(diagnostics[1].diagnostic.message.contains("min_size"));
+    }
+
+    #[test]
+    fn test_included_directories_are_reported_against_their_file() {
+        let tmp = TempDir::new().unwrap();
+        let main = tmp.path().join("m.toml");
+        let fragment = tmp.path().join("conf.d/frag.toml");
+        std::fs::create_dir(tmp.path().join("conf.d")).unwrap();
+        std::fs::write(
+            &fragment,
+            format!(
+                "# team inbox\n[[dirs]]\npath = \"{}\"\ntime_to_archive_hours = 24\ntime_to_deletion_hours = 2\n",
+                tmp.path().display()
+            ),
+        )
+        .unwrap();
+        let source = "include = [\"conf.d/*.toml\"]\n";
+
+        let diagnostics = crate::config::Config::parse(&main, source)
+            .unwrap()
+            .validate();
+
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        let rendered = diagnostics[0].render();
+        assert!(
+            rendered.starts_with(&format!(
+                "error: {}:5:26: time_to_deletion_hours (2)",
+                fragment.display()
+            )),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn test_toml_errors_carry_spans() {
+        let source = "[[dirs]]\npath = \"/tmp\"\ntime_to_archive_hours = \"soon\"\n";
+        let Err(crate::Error::Schema(err)) =
+            crate::config::Config::parse(Path::new("c.toml"), source)
+        else {
+            panic!("expected a schema error");
+        };
+        let rendered = err.render();
+        assert!(rendered.starts_with("error: c.toml:3:25:"), "{rendered}");
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+/// Source of the current instant. A plan reads it once, so ages and archive
+/// names within one run all agree.
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock stopped at a given instant, for tests and for replaying runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// Parse a UTC instant written as RFC 3339 (`2024-03-09T12:00:00Z`), in the
+/// archive suffix format (`20240309T120000Z`), or as a date (`2024-03-09`, midnight).
+pub fn parse_timestamp(raw: &str) -> Result<DateTime<Utc>, String> {
+    let s = raw.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%SZ") {
+        return Ok(t.and_utc());
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_hms_opt(0, 0, 0).expect("midnight is valid").and_utc());
+    }
+    Err(format!(
+        "invalid timestamp {raw:?}: expected e.g. 2024-03-09T12:00:00Z, 20240309T120000Z or 2024-03-09"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        assert_eq!(parse_timestamp("2024-03-09T12:00:00Z"), Ok(expected));
+        assert_eq!(parse_timestamp("2024-03-09T13:00:00+01:00"), Ok(expected));
+        assert_eq!(parse_timestamp("20240309T120000Z"), Ok(expected));
+        assert_eq!(
+            parse_timestamp("2024-03-09"),
+            Ok(Utc.with_ymd_and_hms(2024, 3, 9, 0, 0, 0).unwrap())
+        );
+        assert!(
+            parse_timestamp("yesterday")
+                .unwrap_err()
+                .contains("yesterday")
+        );
+    }
+}
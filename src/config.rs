@@ -0,0 +1,497 @@
+use crate::check::{self, Diagnostic, Located};
+use crate::{DirConfig, Error, Result, paths};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::Spanned;
+use toml::de::{DeArray, DeTable, DeValue};
+
+/// Name used for the source of configs parsed from a string.
+const INLINE_SOURCE: &str = "<string>";
+
+/// A parsed duansheli config: the watched directories, with `[defaults]` and
+/// `include`d files already merged in.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub dirs: Vec<DirConfig>,
+    /// Every file read, with its text, used to locate diagnostics.
+    sources: Vec<(PathBuf, String)>,
+    /// Source and position in that source's `dirs` array of each directory.
+    origins: Vec<(usize, usize)>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/duansheli/config.toml`, falling back to `~/.config`.
+    pub fn default_path() -> PathBuf {
+        let config_home = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| {
+                let home = env::var("HOME").expect("HOME environment variable not set");
+                PathBuf::from(home).join(".config")
+            });
+        config_home.join("duansheli").join("config.toml")
+    }
+
+    /// The default config of the user whose home is `home`, for when their
+    /// environment is not ours, e.g. under `sudo`.
+    pub fn default_path_in(home: &Path) -> PathBuf {
+        home.join(".config").join("duansheli").join("config.toml")
+    }
+
+    /// Read and parse the config at `path`, expanding directory paths.
+    pub fn load(path: &Path) -> Result<Config> {
+        let raw = fs::read_to_string(path).map_err(Error::io("read config", path))?;
+        log::info!("Config Filepath: {}", path.display());
+        let mut config = Config::parse(path, &raw)?;
+        config.expand_paths()?;
+        Ok(config)
+    }
+
+    /// Parse `raw` as if read from `path`, merging its includes (relative to
+    /// `path`) and filling each directory's unset keys from `[defaults]`.
+    /// Top-level `protected_paths` from every file are added to each directory's own.
+    ///
+    /// Values that do not fit the schema are reported as [`Error::Schema`],
+    /// pointing into the file they were written in.
+    /// Directory paths are left as written; see [`Config::expand_paths`].
+    pub fn parse(path: &Path, raw: &str) -> Result<Config> {
+        let mut tables = ConfigTables::default();
+        tables.collect(path, raw.to_string(), &mut Vec::new())?;
+        let documents = tables
+            .sources
+            .iter()
+            .map(|(file, text)| {
+                DeTable::parse(text).map_err(|e| SchemaError::located(file, text, &e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let dirs = tables
+            .dirs
+            .iter()
+            .map(|&(source, position)| {
+                let (file, text) = &tables.sources[source];
+                let dir = documents[source]
+                    .get_ref()
+                    .get("dirs")
+                    .and_then(|dirs| dirs.get_ref().get(position))
+                    .expect("collect only records existing dirs");
+                let (merged, borrowed) = tables.merge(&documents, dir);
+                DirConfig::deserialize(toml::de::Deserializer::from(merged)).map_err(|e| {
+                    // Blame the file a borrowed default or protected path came from,
+                    // unless the error lies within the directory's own table.
+                    let span = e.span().unwrap_or(dir.span());
+                    let within = |r: &Range<usize>| r.start <= span.start && span.end <= r.end;
+                    let own = dir
+                        .get_ref()
+                        .as_table()
+                        .into_iter()
+                        .flatten()
+                        .any(|(k, v)| within(&k.span()) || within(&v.span()));
+                    match borrowed.iter().find(|(r, _)| within(r)) {
+                        Some((_, other)) if !own => {
+                            let (file, text) = &tables.sources[*other];
+                            SchemaError::located(file, text, &e)
+                        }
+                        _ => SchemaError::located(file, text, &e),
+                    }
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Config {
+            dirs,
+            sources: tables.sources,
+            origins: tables.dirs,
+        })
+    }
+
+    /// Expand `~`, environment variables and XDG user directories in every directory path.
+    pub fn expand_paths(&mut self) -> Result<()> {
+        for dir in &mut self.dirs {
+            dir.expand_path()?;
+        }
+        Ok(())
+    }
+
+    /// Semantic problems in the config, such as missing or nested directories,
+    /// each located in the file its directory was written in.
+    pub fn validate(&self) -> Vec<Located> {
+        let dirs: Vec<_> = self
+            .dirs
+            .iter()
+            .zip(&self.origins)
+            .map(|(dir, &(source, position))| (dir.clone(), source, position))
+            .collect();
+        check::check_dirs(&self.sources, &dirs)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Parse and expand a config; includes are resolved relative to the working directory.
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::parse(Path::new(INLINE_SOURCE), raw)?;
+        config.expand_paths()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  directories :")?;
+        for dir in &self.dirs {
+            match &dir.raw_path {
+                Some(raw) if Path::new(raw) != dir.path => {
+                    writeln!(f, "    - {raw} -> {}", dir.path.display())?
+                }
+                _ => writeln!(f, "    - {}", dir.path.display())?,
+            }
+            writeln!(
+                f,
+                "      archive after : {} hours",
+                dir.time_to_archive_hours
+            )?;
+            writeln!(
+                f,
+                "      delete after  : {} hours",
+                dir.time_to_deletion_hours
+            )?;
+            if let Some(interval) = dir.interval {
+                writeln!(f, "      daemon every  : {}", interval)?;
+            }
+            if let Some(watermark) = dir.min_free_space {
+                writeln!(f, "      min free space: {}", watermark)?;
+            }
+            for rule in &dir.rules {
+                writeln!(f, "      rule          : {}", rule)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A config value rejected by the schema or the TOML parser, located in the
+/// file it was written in.
+#[derive(Debug)]
+pub struct SchemaError {
+    pub file: PathBuf,
+    /// Text of `file`, to show the offending line.
+    pub source: String,
+    pub diagnostic: Diagnostic,
+}
+
+impl SchemaError {
+    fn located(file: &Path, source: &str, err: &toml::de::Error) -> Error {
+        Error::Schema(Box::new(SchemaError {
+            file: file.to_path_buf(),
+            source: source.to_string(),
+            diagnostic: Diagnostic::from_toml(err),
+        }))
+    }
+
+    /// The diagnostic with the line it points at, as printed by `duansheli check`.
+    pub fn render(&self) -> String {
+        self.diagnostic.render(&self.source, &self.file)
+    }
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}",
+            self.diagnostic.location(&self.source, &self.file),
+            self.diagnostic.message
+        )
+    }
+}
+
+/// Where the `[defaults]`, `[[dirs]]` and `protected_paths` of a config file
+/// and its includes are; indices refer to `sources`.
+#[derive(Default)]
+struct ConfigTables {
+    /// Every file read, with its text.
+    sources: Vec<(PathBuf, String)>,
+    /// Each key of `[defaults]` and the source it is taken from.
+    defaults: Vec<(String, usize)>,
+    /// Source and position in that source's `dirs` array of each directory.
+    dirs: Vec<(usize, usize)>,
+    /// Sources with a top-level `protected_paths`.
+    protected_paths: Vec<usize>,
+}
+
+impl ConfigTables {
+    /// Record tables from `path`, then from its `include` globs (relative to
+    /// the file's directory). The including file's defaults win over included ones.
+    fn collect(&mut self, path: &Path, raw: String, visited: &mut Vec<PathBuf>) -> Result<()> {
+        let invalid = |message: String| Error::Config(format!("{}: {message}", path.display()));
+        let mut table: toml::Table =
+            toml::from_str(&raw).map_err(|e| SchemaError::located(path, &raw, &e))?;
+        visited.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        let source = self.sources.len();
+        self.sources.push((path.to_path_buf(), raw));
+
+        if let Some(defaults) = table.remove("defaults") {
+            for key in table_of(defaults, "defaults").map_err(invalid)?.keys() {
+                if !self.defaults.iter().any(|(k, _)| k == key) {
+                    self.defaults.push((key.clone(), source));
+                }
+            }
+        }
+        for (position, dir) in table
+            .remove("dirs")
+            .map_or(Ok(Vec::new()), |v| array_of(v, "dirs"))
+            .map_err(invalid)?
+            .into_iter()
+            .enumerate()
+        {
+            table_of(dir, "dirs").map_err(invalid)?;
+            self.dirs.push((source, position));
+        }
+        if let Some(protected) = table.remove("protected_paths") {
+            array_of(protected, "protected_paths").map_err(invalid)?;
+            self.protected_paths.push(source);
+        }
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let include_error = |e: &dyn fmt::Display| invalid(format!("include: {e}"));
+        for pattern in table
+            .remove("include")
+            .map_or(Ok(Vec::new()), |v| array_of(v, "include"))
+            .map_err(invalid)?
+        {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| invalid("include entries must be strings".to_string()))?;
+            let pattern = base.join(paths::expand_path(pattern).map_err(|e| include_error(&e))?);
+            let mut matches: Vec<PathBuf> = glob::glob(&pattern.to_string_lossy())
+                .map_err(|e| include_error(&e))?
+                .collect::<Result<_, _>>()
+                .map_err(|e| include_error(&e))?;
+            matches.sort();
+
+            for fragment in matches {
+                let resolved =
+                    fs::canonicalize(&fragment).map_err(Error::io("read config", &fragment))?;
+                if visited.contains(&resolved) {
+                    continue;
+                }
+                let fragment_raw =
+                    fs::read_to_string(&fragment).map_err(Error::io("read config", &fragment))?;
+                self.collect(&fragment, fragment_raw, visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `dir` with unset keys filled from `[defaults]` and the top-level
+    /// `protected_paths` appended, along with the spans of everything
+    /// borrowed from other tables and the source each came from.
+    fn merge<'i>(
+        &self,
+        documents: &[Spanned<DeTable<'i>>],
+        dir: &Spanned<DeValue<'i>>,
+    ) -> (Spanned<DeTable<'i>>, Vec<(Range<usize>, usize)>) {
+        let mut table = dir.get_ref().as_table().cloned().unwrap_or_default();
+        let mut borrowed = Vec::new();
+        let top = |source: usize, key: &str| documents[source].get_ref().get(key);
+
+        for (key, source) in &self.defaults {
+            if table.contains_key(key.as_str()) {
+                continue;
+            }
+            let defaults = top(*source, "defaults").and_then(|d| d.get_ref().as_table());
+            if let Some((key, value)) = defaults.and_then(|d| d.get_key_value(key.as_str())) {
+                borrowed.push((key.span(), *source));
+                borrowed.push((value.span(), *source));
+                table.insert(key.clone(), value.clone());
+            }
+        }
+
+        let protected_key = Spanned::new(dir.span(), Cow::Borrowed("protected_paths"));
+        let protected = table
+            .entry(protected_key)
+            .or_insert_with(|| Spanned::new(dir.span(), DeValue::Array(DeArray::new())));
+        if let DeValue::Array(protected) = protected.get_mut() {
+            for &source in &self.protected_paths {
+                let paths = top(source, "protected_paths").and_then(|p| p.get_ref().as_array());
+                for path in paths.into_iter().flat_map(|p| p.iter()) {
+                    borrowed.push((path.span(), source));
+                    protected.push(path.clone());
+                }
+            }
+        }
+        (Spanned::new(dir.span(), table), borrowed)
+    }
+}
+
+fn table_of(value: toml::Value, key: &str) -> Result<toml::Table, String> {
+    match value {
+        toml::Value::Table(table) => Ok(table),
+        other => Err(format!(
+            "{key}: expected a table, found {}",
+            other.type_str()
+        )),
+    }
+}
+
+fn array_of(value: toml::Value, key: &str) -> Result<Vec<toml::Value>, String> {
+    match value {
+        toml::Value::Array(array) => Ok(array),
+        other => Err(format!(
+            "{key}: expected an array, found {}",
+            other.type_str()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_defaults_and_includes_are_merged() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir(tmp.path().join("conf.d")).unwrap();
+        fs::write(
+            tmp.path().join("conf.d/team.toml"),
+            "[defaults]\ntime_to_archive_hours = 24\ntime_to_deletion_hours = 168\ninterval = \"30m\"\n",
+        )
+        .unwrap();
+        fs::write(
+            tmp.path().join("conf.d/mine.toml"),
+            "[[dirs]]\npath = \"/srv/scratch\"\ntime_to_deletion_hours = 48\n",
+        )
+        .unwrap();
+        let main = tmp.path().join("config.toml");
+        let raw = "include = [\"conf.d/*.toml\"]\nprotected_paths = [\"/srv/photos\"]\n\n[defaults]\ninterval = \"1h\"\n\n[[dirs]]\npath = \"/srv/downloads\"\n";
+
+        let config = Config::parse(&main, raw).unwrap();
+
+        assert_eq!(config.dirs.len(), 2);
+        assert_eq!(config.dirs[0].path, PathBuf::from("/srv/downloads"));
+        assert_eq!(config.dirs[0].time_to_archive_hours, 24);
+        assert_eq!(config.dirs[0].interval.unwrap().to_string(), "1h");
+        assert_eq!(config.dirs[1].path, PathBuf::from("/srv/scratch"));
+        assert_eq!(config.dirs[1].time_to_deletion_hours, 48);
+        assert_eq!(
+            config.dirs[1].protected_paths,
+            [PathBuf::from("/srv/photos")]
+        );
+    }
+
+    #[test]
+    fn test_missing_threshold_names_the_directory() {
+        let err = Config::parse(Path::new("c.toml"), "[[dirs]]\npath = \"/srv\"\n").unwrap_err();
+        assert!(
+            err.to_string().starts_with("c.toml:1:1: missing field"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_schema_errors_point_into_the_included_file() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(
+            tmp.path().join("team.toml"),
+            "[defaults]\ntime_to_archive_hours = \"soon\"\n",
+        )
+        .unwrap();
+        let main = tmp.path().join("config.toml");
+        let raw =
+            "include = [\"team.toml\"]\n\n[[dirs]]\npath = \"/srv\"\ntime_to_deletion_hours = 2\n";
+
+        let err = Config::parse(&main, raw).unwrap_err();
+        let Error::Schema(schema) = &err else {
+            panic!("expected a schema error, got {err}");
+        };
+        assert_eq!(schema.file, tmp.path().join("team.toml"));
+        assert!(
+            err.to_string()
+                .ends_with("team.toml:2:25: invalid type: string \"soon\", expected u64"),
+            "{err}"
+        );
+
+        let own = "[[dirs]]\npath = \"/srv\"\ntime_to_archive_hours = \"soon\"\n";
+        let err = Config::parse(&main, own).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("config.toml:3:25: invalid type: string \"soon\", expected u64"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_zero_interval_fails_to_load() {
+        let err = Config::parse(
+            Path::new("c.toml"),
+            "[[dirs]]\npath = \"/srv\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\ninterval = \"0s\"\n",
+        )
+        .unwrap_err();
+        let Error::Schema(schema) = &err else {
+            panic!("expected a schema error, got {err:?}");
+        };
+        assert!(
+            schema
+                .to_string()
+                .starts_with("c.toml:5:12: duration must be positive"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_load_expands_paths_and_validates() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(
+            &path,
+            format!(
+                "[[dirs]]\npath = \"{}/inbox\"\ntime_to_archive_hours = 2\ntime_to_deletion_hours = 1\n",
+                tmp.path().display()
+            ),
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+
+        assert_eq!(config.dirs[0].path, tmp.path().join("inbox"));
+        let messages: Vec<_> = config
+            .validate()
+            .into_iter()
+            .map(|d| d.diagnostic.message)
+            .collect();
+        assert!(
+            messages.iter().any(|m| m.contains("does not exist")),
+            "{messages:?}"
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.contains("time_to_deletion_hours (1)")),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        let config: Config =
+            "[[dirs]]\npath = \"/srv\"\ntime_to_archive_hours = 1\ntime_to_deletion_hours = 2\n"
+                .parse()
+                .unwrap();
+        assert_eq!(config.dirs[0].raw_path.as_deref(), Some("/srv"));
+        assert!(
+            "[[dirs]]\npath = 3\n"
+                .parse::<Config>()
+                .unwrap_err()
+                .to_string()
+                .starts_with("<string>:2:8: ")
+        );
+    }
+}
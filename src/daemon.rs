@@ -0,0 +1,199 @@
+use crate::{DirConfig, Result, RunOptions, declutter_directory_with};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval for directories that don't set `interval`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest uninterrupted sleep, so signals are noticed promptly.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set by SIGTERM/SIGINT; checked between actions and between runs.
+pub static TERMINATE: AtomicBool = AtomicBool::new(false);
+/// Set by SIGHUP; the config is reloaded before the next run.
+pub static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => TERMINATE.store(true, Ordering::SeqCst),
+    }
+}
+
+pub(crate) fn install_signal_handlers() {
+    for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        // SAFETY: the handler only stores to atomics, which is async-signal-safe.
+        unsafe {
+            libc::signal(signal, handle_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+struct Job {
+    cfg: DirConfig,
+    interval: Duration,
+    next_due: Instant,
+}
+
+/// Tracks when each watched directory is next due.
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    /// Schedule `dirs`, all due immediately.
+    pub fn new(dirs: Vec<DirConfig>, now: Instant) -> Self {
+        let mut scheduler = Scheduler { jobs: Vec::new() };
+        scheduler.reload(dirs, now);
+        scheduler
+    }
+
+    /// Replace the scheduled directories, keeping the due time of paths that
+    /// were already scheduled; new paths are due immediately.
+    pub fn reload(&mut self, dirs: Vec<DirConfig>, now: Instant) {
+        let previous: Vec<(PathBuf, Instant)> = self
+            .jobs
+            .drain(..)
+            .map(|job| (job.cfg.path, job.next_due))
+            .collect();
+
+        self.jobs = dirs
+            .into_iter()
+            .map(|cfg| {
+                let next_due = previous
+                    .iter()
+                    .find(|(path, _)| *path == cfg.path)
+                    .map_or(now, |(_, due)| *due);
+                let interval = cfg.interval.map_or(DEFAULT_INTERVAL, |i| i.0);
+                Job {
+                    cfg,
+                    interval,
+                    next_due,
+                }
+            })
+            .collect();
+    }
+
+    /// Earliest due time across all directories.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.jobs.iter().map(|job| job.next_due).min()
+    }
+
+    /// Directories due at `now`, rescheduled one interval later.
+    pub fn take_due(&mut self, now: Instant) -> Vec<DirConfig> {
+        self.jobs
+            .iter_mut()
+            .filter(|job| job.next_due <= now)
+            .map(|job| {
+                job.next_due = now + job.interval;
+                job.cfg.clone()
+            })
+            .collect()
+    }
+}
+
+/// Sleep until `deadline`, waking early if a signal arrives.
+fn sleep_until(deadline: Instant) {
+    while !TERMINATE.load(Ordering::SeqCst) && !RELOAD.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep((deadline - now).min(POLL_INTERVAL));
+    }
+}
+
+/// Run every directory on its own interval until SIGTERM or SIGINT.
+///
+/// `load_config` is called at startup and again on each SIGHUP; if a reload
+/// fails, the previous configuration stays in effect.
+pub fn run<F>(load_config: F, opts: &RunOptions) -> Result<()>
+where
+    F: Fn() -> Result<Vec<DirConfig>>,
+{
+    install_signal_handlers();
+    let opts = RunOptions {
+        cancel: Some(&TERMINATE),
+        ..opts.clone()
+    };
+
+    let mut scheduler = Scheduler::new(load_config()?, Instant::now());
+    log::info!("Daemon started");
+
+    while !TERMINATE.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            match load_config() {
+                Ok(dirs) => {
+                    log::info!("Reloaded config with {} directories", dirs.len());
+                    scheduler.reload(dirs, Instant::now());
+                }
+                Err(e) => log::error!("Config reload failed, keeping previous config: {e}"),
+            }
+        }
+
+        for cfg in scheduler.take_due(Instant::now()) {
+            if TERMINATE.load(Ordering::SeqCst) {
+                break;
+            }
+            log::info!("Processing directory: {}", cfg.path.display());
+            let path = cfg.path.clone();
+            if let Err(e) = declutter_directory_with(cfg, &opts) {
+                log::error!("{}: {e}", path.display());
+            }
+        }
+
+        match scheduler.next_due() {
+            Some(deadline) => sleep_until(deadline),
+            None => sleep_until(Instant::now() + DEFAULT_INTERVAL),
+        }
+    }
+
+    log::info!("Daemon stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duration::Interval;
+
+    fn dir(path: &str, interval_secs: Option<u64>) -> DirConfig {
+        DirConfig {
+            path: PathBuf::from(path),
+            interval: interval_secs.map(|s| Interval(Duration::from_secs(s))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_each_directory_runs_on_its_own_interval() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(vec![dir("/a", Some(60)), dir("/b", None)], start);
+
+        assert_eq!(scheduler.take_due(start).len(), 2);
+        assert_eq!(scheduler.next_due(), Some(start + Duration::from_secs(60)));
+
+        let later = start + Duration::from_secs(60);
+        let due = scheduler.take_due(later);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].path, PathBuf::from("/a"));
+        assert!(scheduler.take_due(later).is_empty());
+    }
+
+    #[test]
+    fn test_reload_keeps_due_times_of_known_paths() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(vec![dir("/a", Some(60))], start);
+        scheduler.take_due(start);
+
+        let later = start + Duration::from_secs(10);
+        scheduler.reload(vec![dir("/a", Some(60)), dir("/new", Some(60))], later);
+
+        let due = scheduler.take_due(later);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].path, PathBuf::from("/new"));
+        assert_eq!(scheduler.next_due(), Some(start + Duration::from_secs(60)));
+    }
+}
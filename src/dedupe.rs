@@ -0,0 +1,138 @@
+use crate::filesystem::Filesystem;
+use crate::{DirEntryWithAge, inuse};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Which copy of a set of identical files survives deduplication.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeKeep {
+    /// The least recently modified copy, usually the first download.
+    #[default]
+    Oldest,
+    /// The most recently modified copy.
+    Newest,
+}
+
+/// What happens to the copies that are not kept.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeAction {
+    #[default]
+    Archive,
+    Delete,
+}
+
+/// A set of files with identical contents: the copy to keep and the rest.
+#[derive(Debug, PartialEq)]
+pub struct Duplicates {
+    pub kept: PathBuf,
+    pub copies: Vec<PathBuf>,
+}
+
+/// Group the non-empty regular files among `entries` by content.
+///
+/// Files are bucketed by size first, so only files sharing a size are read
+/// and hashed. Unreadable files are left out with a warning.
+pub fn find_duplicates(
+    fs: &dyn Filesystem,
+    entries: &[DirEntryWithAge],
+    keep: DedupeKeep,
+) -> Vec<Duplicates> {
+    let mut by_size: HashMap<u64, Vec<&DirEntryWithAge>> = HashMap::new();
+    for entry in entries {
+        let path = Path::new(&entry.path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let regular = fs.metadata(path).is_ok_and(|m| !m.is_dir && !m.is_symlink);
+        if regular && entry.size_bytes > 0 && !inuse::is_partial_download(&name) {
+            by_size.entry(entry.size_bytes).or_default().push(entry);
+        }
+    }
+
+    let mut groups = Vec::new();
+    for candidates in by_size.into_values().filter(|c| c.len() > 1) {
+        let mut by_hash: HashMap<[u8; 32], Vec<&DirEntryWithAge>> = HashMap::new();
+        for entry in candidates {
+            match hash_file(fs, Path::new(&entry.path)) {
+                Ok(hash) => by_hash.entry(hash).or_default().push(entry),
+                Err(e) => log::warn!("Not deduplicating {}: {}", entry.path, e),
+            }
+        }
+        for mut same in by_hash.into_values().filter(|s| s.len() > 1) {
+            // Ages tie when copies land in the same second; fall back to the
+            // shortest, then first, name.
+            same.sort_by(|a, b| {
+                let by_age = match keep {
+                    DedupeKeep::Oldest => b
+                        .seconds_since_modification
+                        .cmp(&a.seconds_since_modification),
+                    DedupeKeep::Newest => a
+                        .seconds_since_modification
+                        .cmp(&b.seconds_since_modification),
+                };
+                by_age
+                    .then(a.path.len().cmp(&b.path.len()))
+                    .then(a.path.cmp(&b.path))
+            });
+            let mut paths = same.into_iter().map(|e| PathBuf::from(&e.path));
+            let kept = paths.next().expect("groups hold at least two files");
+            groups.push(Duplicates {
+                kept,
+                copies: paths.collect(),
+            });
+        }
+    }
+    groups.sort_by(|a, b| a.kept.cmp(&b.kept));
+    groups
+}
+
+/// SHA-256 of a file's contents, read through `fs`.
+pub(crate) fn hash_file(fs: &dyn Filesystem, path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs.open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use crate::list_dir_with_meta;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_find_duplicates() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let fs = InMemoryFs::new();
+        fs.add_file("/w/file.pdf", "same", t)
+            .add_file("/w/file (1).pdf", "same", t + Duration::from_secs(60))
+            .add_file("/w/file (2).pdf", "same", t + Duration::from_secs(120))
+            .add_file("/w/other.pdf", "diff", t)
+            .add_file("/w/empty", "", t)
+            .add_file("/w/empty (1)", "", t)
+            .add_file("/w/file.pdf.part", "same", t)
+            .add_dir("/w/dir", t);
+        let now = t + Duration::from_secs(3600);
+        let entries =
+            list_dir_with_meta(&fs, Path::new("/w"), None, now, Default::default()).unwrap();
+
+        let oldest = find_duplicates(&fs, &entries, DedupeKeep::Oldest);
+        assert_eq!(oldest.len(), 1, "{oldest:?}");
+        assert_eq!(oldest[0].kept, PathBuf::from("/w/file.pdf"));
+        let mut copies = oldest[0].copies.clone();
+        copies.sort();
+        assert_eq!(
+            copies,
+            [
+                PathBuf::from("/w/file (1).pdf"),
+                PathBuf::from("/w/file (2).pdf")
+            ]
+        );
+
+        let newest = find_duplicates(&fs, &entries, DedupeKeep::Newest);
+        assert_eq!(newest[0].kept, PathBuf::from("/w/file (2).pdf"));
+    }
+}
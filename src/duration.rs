@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+/// A duration in config, written like `"90s"`, `"15m"`, `"1h30m"` or `"7d"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Interval(pub Duration);
+
+impl TryFrom<String> for Interval {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        parse_duration(&raw).map(Interval)
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut secs = self.0.as_secs();
+        if secs == 0 {
+            return write!(f, "0s");
+        }
+        for (unit, len) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+            if secs >= len {
+                write!(f, "{}{}", secs / len, unit)?;
+                secs %= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a duration made of `<number><unit>` parts, with units `s`, `m`, `h`, `d` and `w`.
+///
+/// Durations are used as intervals between runs, so zero is rejected.
+pub fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let s = raw.trim();
+    if s.is_empty() {
+        return Err("empty duration".to_string());
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("invalid duration: {raw:?}"));
+        }
+        let value: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| format!("invalid duration: {raw:?}"))?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_len] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            "" => return Err(format!("missing unit in duration {raw:?}")),
+            other => return Err(format!("unknown unit {other:?} in duration {raw:?}")),
+        };
+        rest = &rest[unit_len..];
+
+        total = value
+            .checked_mul(multiplier)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("duration too large: {raw:?}"))?;
+    }
+
+    if total == 0 {
+        return Err(format!("duration must be positive: {raw:?}"));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3 fortnights").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("0h0m").is_err());
+    }
+
+    #[test]
+    fn test_display_interval() {
+        assert_eq!(Interval(Duration::from_secs(5400)).to_string(), "1h30m");
+        assert_eq!(Interval(Duration::from_secs(86400)).to_string(), "1d");
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::SchemaError;
+use crate::lock::LockedError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can make a duansheli operation fail.
+#[derive(Debug)]
+pub enum Error {
+    /// Refused to operate on a protected directory; `reason` says why.
+    DangerousPath { path: PathBuf, reason: String },
+    /// A watched directory does not exist or is not a directory.
+    MissingDirectory { path: PathBuf },
+    /// An I/O operation failed; `action` says what was being done to `path`.
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The config could not be read, parsed or resolved, or asks for something unsafe.
+    Config(String),
+    /// A config file is not valid TOML or a value does not fit the schema.
+    Schema(Box<SchemaError>),
+    /// Another run holds the directory's lock.
+    Lock(LockedError),
+    /// A plan exceeded one of the directory's per-run limits; `reason` says which.
+    LimitExceeded { path: PathBuf, reason: String },
+}
+
+impl Error {
+    /// Adapter for `map_err` that records what was being done to `path`.
+    pub fn io(action: &'static str, path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.into();
+        move |source| Error::Io {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DangerousPath { path, reason } => {
+                write!(
+                    f,
+                    "refusing to operate on dangerous path {}: {reason}",
+                    path.display()
+                )
+            }
+            Error::MissingDirectory { path } => {
+                write!(f, "{} does not exist or is not a directory", path.display())
+            }
+            Error::Io {
+                action,
+                path,
+                source,
+            } if path.as_os_str().is_empty() => {
+                write!(f, "cannot {action}: {source}")
+            }
+            Error::Io {
+                action,
+                path,
+                source,
+            } => write!(f, "cannot {action} {}: {source}", path.display()),
+            Error::Config(message) => write!(f, "{message}"),
+            Error::Schema(err) => write!(f, "{err}"),
+            Error::Lock(locked) => write!(f, "{locked}"),
+            Error::LimitExceeded { path, reason } => {
+                write!(f, "not touching {}: {reason}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<LockedError> for Error {
+    fn from(locked: LockedError) -> Self {
+        Error::Lock(locked)
+    }
+}
@@ -0,0 +1,838 @@
+use crate::lock::{DirLock, LockedError};
+use crate::space::{self, DiskUsage};
+use std::any::Any;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Symlinks followed before giving up, as in Linux's `MAXSYMLINKS`.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// What the planner needs to know about an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    pub len: u64,
+    pub modified: SystemTime,
+    /// ID of the device holding the entry.
+    pub dev: u64,
+    /// Inode number; with `dev` it identifies the file behind every hardlink.
+    pub ino: u64,
+    /// Number of hardlinks to the entry.
+    pub nlink: u64,
+}
+
+/// The filesystem operations used to plan and execute a run.
+pub trait Filesystem {
+    /// Paths of the entries directly inside `dir`, in no particular order.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Metadata of `path` itself; a symlink is reported as a symlink, not as its target.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Metadata of what `path` points to, following symlinks.
+    fn stat(&self, path: &Path) -> io::Result<Metadata>;
+    /// Absolute path of `path` with every symlink resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Every mount point, as canonical paths.
+    fn mount_points(&self) -> io::Result<Vec<PathBuf>>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Copy a single file, returning the number of bytes copied.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+    /// Open a file for reading, following symlinks.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+    /// Create `link` as another name for the file at `original`.
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+    /// Set the modification time of a file, following symlinks.
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()>;
+    /// Size and free space of the filesystem holding `path`.
+    fn disk_usage(&self, path: &Path) -> io::Result<DiskUsage>;
+    /// Lock the watched directory `dir` against other runs using `state_dir`
+    /// until the returned guard is dropped; see [`DirLock::acquire`].
+    fn lock_dir(&self, state_dir: &Path, dir: &Path, wait: bool) -> crate::Result<Box<dyn Any>>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_dir)
+    }
+}
+
+/// The real filesystem, via `std::fs`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        convert(fs::symlink_metadata(path)?)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        convert(fs::metadata(path)?)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn mount_points(&self) -> io::Result<Vec<PathBuf>> {
+        crate::mounts::mount_points()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        fs::File::open(path)?.set_modified(modified)
+    }
+
+    fn disk_usage(&self, path: &Path) -> io::Result<DiskUsage> {
+        space::disk_usage(path)
+    }
+
+    fn lock_dir(&self, state_dir: &Path, dir: &Path, wait: bool) -> crate::Result<Box<dyn Any>> {
+        Ok(Box::new(DirLock::acquire(state_dir, dir, wait)?))
+    }
+}
+
+fn convert(meta: fs::Metadata) -> io::Result<Metadata> {
+    Ok(Metadata {
+        is_dir: meta.is_dir(),
+        is_symlink: meta.file_type().is_symlink(),
+        len: meta.len(),
+        modified: meta.modified()?,
+        dev: meta.dev(),
+        ino: meta.ino(),
+        nlink: meta.nlink(),
+    })
+}
+
+/// Operations of [`Filesystem`] that [`InMemoryFs`] can be told to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsOp {
+    ReadDir,
+    Metadata,
+    Rename,
+    RemoveFile,
+    RemoveDirAll,
+    CreateDirAll,
+    Copy,
+    Open,
+    HardLink,
+    SetModified,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    /// `len` is normally `contents.len()`; see [`InMemoryFs::add_sized_file`].
+    /// Hardlinks are nodes sharing an `ino`; nothing writes to files after
+    /// creation, so their contents never diverge.
+    File {
+        contents: Vec<u8>,
+        len: u64,
+        modified: SystemTime,
+        ino: u64,
+    },
+    Dir {
+        modified: SystemTime,
+    },
+    Symlink {
+        target: PathBuf,
+        modified: SystemTime,
+    },
+}
+
+impl Node {
+    /// Metadata with `dev` left at 0; see [`State::metadata`].
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File {
+                len, modified, ino, ..
+            } => Metadata {
+                is_dir: false,
+                is_symlink: false,
+                len: *len,
+                modified: *modified,
+                dev: 0,
+                ino: *ino,
+                nlink: 1,
+            },
+            Node::Dir { modified } => Metadata {
+                is_dir: true,
+                is_symlink: false,
+                len: 0,
+                modified: *modified,
+                dev: 0,
+                ino: 0,
+                nlink: 1,
+            },
+            Node::Symlink { target, modified } => Metadata {
+                is_dir: false,
+                is_symlink: true,
+                len: target.as_os_str().len() as u64,
+                modified: *modified,
+                dev: 0,
+                ino: 0,
+                nlink: 1,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    nodes: BTreeMap<PathBuf, Node>,
+    failures: Vec<(FsOp, PathBuf, io::ErrorKind)>,
+    /// Modification time for directories created through the trait.
+    now: Option<SystemTime>,
+    /// Mount points and the device IDs of the filesystems mounted there.
+    mounts: BTreeMap<PathBuf, u64>,
+    /// Inode number of the most recently created file.
+    last_ino: u64,
+    /// Every directory listed through the trait, in order.
+    read_dirs: Vec<PathBuf>,
+    /// Total bytes for [`Filesystem::disk_usage`]; see [`InMemoryFs::set_capacity`].
+    capacity: Option<u64>,
+    /// Directories locked through [`Filesystem::lock_dir`].
+    locked: HashSet<PathBuf>,
+}
+
+impl State {
+    fn check(&self, op: FsOp, path: &Path) -> io::Result<()> {
+        match self.failures.iter().find(|(o, p, _)| *o == op && p == path) {
+            Some((_, _, kind)) => Err(io::Error::new(*kind, format!("injected {op:?} failure"))),
+            None => Ok(()),
+        }
+    }
+
+    fn node(&self, path: &Path) -> io::Result<&Node> {
+        self.nodes.get(path).ok_or_else(|| not_found(path))
+    }
+
+    /// Metadata of the node at `path`, on the device of the innermost mount holding it.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let dev = self
+            .mounts
+            .iter()
+            .filter(|(mount, _)| path.starts_with(mount))
+            .max_by_key(|(mount, _)| mount.components().count())
+            .map_or(0, |(_, dev)| *dev);
+        let meta = self.node(path)?.metadata();
+        let nlink = if meta.is_dir || meta.is_symlink {
+            1
+        } else {
+            self.files_with_ino(meta.ino).count() as u64
+        };
+        Ok(Metadata { dev, nlink, ..meta })
+    }
+
+    /// Paths of the hardlinks to the file with inode `ino`.
+    fn files_with_ino(&self, ino: u64) -> impl Iterator<Item = &PathBuf> {
+        self.nodes
+            .iter()
+            .filter(move |(_, node)| matches!(node, Node::File { ino: i, .. } if *i == ino))
+            .map(|(p, _)| p)
+    }
+
+    fn next_ino(&mut self) -> u64 {
+        self.last_ino += 1;
+        self.last_ino
+    }
+
+    fn require_parent_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent().map(|parent| self.node(parent)) {
+            Some(Ok(Node::Dir { .. })) | None => Ok(()),
+            Some(Ok(_)) => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            Some(Err(e)) => Err(e),
+        }
+    }
+
+    /// `path` and everything below it.
+    fn subtree(&self, path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .keys()
+            .filter(|p| p.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn mkdirs(&mut self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        for ancestor in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            match self.nodes.get(ancestor) {
+                Some(Node::Dir { .. }) => {}
+                Some(_) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+                None => {
+                    self.nodes
+                        .insert(ancestor.to_path_buf(), Node::Dir { modified });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `path` with every symlink resolved, like `realpath(3)`. The result need
+    /// not exist unless `must_exist` is set.
+    fn resolve(&self, path: &Path, must_exist: bool) -> io::Result<PathBuf> {
+        let mut hops = 0;
+        let components = |p: &Path| -> Vec<PathBuf> {
+            p.components()
+                .rev()
+                .map(|c| PathBuf::from(c.as_os_str()))
+                .collect()
+        };
+        let mut pending = components(path);
+        let mut resolved = PathBuf::new();
+        while let Some(component) = pending.pop() {
+            match component.components().next() {
+                Some(Component::ParentDir) => {
+                    resolved.pop();
+                }
+                Some(Component::CurDir) => {}
+                _ => resolved.push(component),
+            }
+            if let Some(Node::Symlink { target, .. }) = self.nodes.get(&resolved) {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                pending.extend(components(target));
+                resolved.pop();
+            }
+        }
+        if must_exist {
+            self.node(&resolved)?;
+        }
+        Ok(resolved)
+    }
+
+    /// `path` with symlinks in its parent resolved, leaving the final component as is.
+    fn resolve_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok(self.resolve(parent, false)?.join(name)),
+            _ => Ok(path.to_path_buf()),
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// A filesystem held in memory, for deterministic tests.
+///
+/// Entries get explicit modification times, and [`InMemoryFs::fail`] makes a
+/// chosen operation on a chosen path return an error until the end of the test.
+/// Symlinks are resolved by lookups (`read_dir`, `metadata`, `stat`,
+/// `canonicalize`, `copy`); other operations take paths literally.
+#[derive(Debug, Default)]
+pub struct InMemoryFs {
+    state: Arc<Mutex<State>>,
+}
+
+/// Releases a directory locked through [`InMemoryFs`] when dropped.
+struct InMemoryLock {
+    state: Arc<Mutex<State>>,
+    dir: PathBuf,
+}
+
+impl Drop for InMemoryLock {
+    fn drop(&mut self) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.locked.remove(&self.dir);
+    }
+}
+
+impl InMemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create `path` and any missing parents as directories.
+    pub fn add_dir(&self, path: impl AsRef<Path>, modified: SystemTime) -> &Self {
+        self.lock()
+            .mkdirs(path.as_ref(), modified)
+            .expect("add_dir path crosses a file");
+        self
+    }
+
+    /// Create a file, along with any missing parent directories.
+    pub fn add_file(
+        &self,
+        path: impl AsRef<Path>,
+        contents: impl Into<Vec<u8>>,
+        modified: SystemTime,
+    ) -> &Self {
+        let path = path.as_ref();
+        let mut state = self.lock();
+        if let Some(parent) = path.parent() {
+            state
+                .mkdirs(parent, modified)
+                .expect("add_file path crosses a file");
+        }
+        let contents = contents.into();
+        let len = contents.len() as u64;
+        let ino = state.next_ino();
+        state.nodes.insert(
+            path.to_path_buf(),
+            Node::File {
+                contents,
+                len,
+                modified,
+                ino,
+            },
+        );
+        self
+    }
+
+    /// Create a file that reports `len` bytes without holding them; it reads as empty.
+    pub fn add_sized_file(&self, path: impl AsRef<Path>, len: u64, modified: SystemTime) -> &Self {
+        self.add_file(&path, Vec::new(), modified);
+        if let Some(Node::File { len: stored, .. }) = self.lock().nodes.get_mut(path.as_ref()) {
+            *stored = len;
+        }
+        self
+    }
+
+    /// Use `now` instead of the system time for directories created by
+    /// [`Filesystem::create_dir_all`].
+    pub fn set_now(&self, now: SystemTime) -> &Self {
+        self.lock().now = Some(now);
+        self
+    }
+
+    /// Create a symlink at `path` pointing to `target`, which may be relative
+    /// to `path`'s directory.
+    pub fn add_symlink(
+        &self,
+        path: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+        modified: SystemTime,
+    ) -> &Self {
+        let path = path.as_ref();
+        let mut state = self.lock();
+        if let Some(parent) = path.parent() {
+            state
+                .mkdirs(parent, modified)
+                .expect("add_symlink path crosses a file");
+        }
+        let target = target.as_ref().to_path_buf();
+        state
+            .nodes
+            .insert(path.to_path_buf(), Node::Symlink { target, modified });
+        self
+    }
+
+    /// Make `path` a mount point of device `dev`; everything else is on device 0.
+    pub fn add_mount(&self, path: impl AsRef<Path>, dev: u64) -> &Self {
+        self.lock().mounts.insert(path.as_ref().to_path_buf(), dev);
+        self
+    }
+
+    /// Report a filesystem of `total_bytes` from `disk_usage`, with whatever
+    /// files do not take up available; without a capacity it is unsupported.
+    pub fn set_capacity(&self, total_bytes: u64) -> &Self {
+        self.lock().capacity = Some(total_bytes);
+        self
+    }
+
+    /// Make every `op` on `path` fail with `kind`.
+    pub fn fail(&self, op: FsOp, path: impl AsRef<Path>, kind: io::ErrorKind) -> &Self {
+        self.lock()
+            .failures
+            .push((op, path.as_ref().to_path_buf(), kind));
+        self
+    }
+
+    /// Contents of the file at `path`, if there is one.
+    pub fn contents(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        match self.lock().nodes.get(path.as_ref()) {
+            Some(Node::File { contents, .. }) => Some(contents.clone()),
+            _ => None,
+        }
+    }
+
+    /// Directories listed so far, in order.
+    pub fn read_dirs(&self) -> Vec<PathBuf> {
+        self.lock().read_dirs.clone()
+    }
+
+    /// Every path in the filesystem, sorted.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().nodes.keys().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Filesystem for InMemoryFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut state = self.lock();
+        state.read_dirs.push(dir.to_path_buf());
+        state.check(FsOp::ReadDir, dir)?;
+        let resolved = state.resolve(dir, true)?;
+        match state.node(&resolved)? {
+            Node::Dir { .. } => Ok(state
+                .nodes
+                .keys()
+                .filter(|p| p.parent() == Some(&resolved))
+                .map(|p| dir.join(p.file_name().expect("child paths have names")))
+                .collect()),
+            _ => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.lock();
+        state.check(FsOp::Metadata, path)?;
+        state.metadata(&state.resolve_parent(path)?)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.lock();
+        state.check(FsOp::Metadata, path)?;
+        state.metadata(&state.resolve(path, true)?)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.lock().resolve(path, true)
+    }
+
+    fn mount_points(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(self.lock().mounts.keys().cloned().collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::Rename, from)?;
+        let is_dir = state.node(from)?.metadata().is_dir;
+        state.require_parent_dir(to)?;
+        if to.starts_with(from) && to != from {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        match state.nodes.get(to) {
+            Some(Node::Dir { .. }) if !is_dir => {
+                return Err(io::Error::from(io::ErrorKind::IsADirectory));
+            }
+            Some(Node::Dir { .. }) if state.subtree(to).len() > 1 => {
+                return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty));
+            }
+            Some(Node::File { .. }) if is_dir => {
+                return Err(io::Error::from(io::ErrorKind::NotADirectory));
+            }
+            _ => {}
+        }
+
+        for old in state.subtree(to) {
+            state.nodes.remove(&old);
+        }
+        for old in state.subtree(from) {
+            let node = state.nodes.remove(&old).expect("subtree paths exist");
+            let new = to.join(
+                old.strip_prefix(from)
+                    .expect("subtree paths share the prefix"),
+            );
+            state.nodes.insert(new, node);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::RemoveFile, path)?;
+        match state.node(path)? {
+            Node::Dir { .. } => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            _ => {
+                state.nodes.remove(path);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::RemoveDirAll, path)?;
+        match state.node(path)? {
+            Node::Dir { .. } => {
+                for old in state.subtree(path) {
+                    state.nodes.remove(&old);
+                }
+                Ok(())
+            }
+            // Like std::fs::remove_dir_all, a symlink is removed without following it.
+            Node::Symlink { .. } => {
+                state.nodes.remove(path);
+                Ok(())
+            }
+            Node::File { .. } => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::CreateDirAll, path)?;
+        let now = state.now.unwrap_or_else(SystemTime::now);
+        state.mkdirs(path, now)
+    }
+
+    /// Copies keep the source's modification time.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut state = self.lock();
+        state.check(FsOp::Copy, from)?;
+        let mut node = match state.node(&state.resolve(from, true)?)? {
+            node @ Node::File { .. } => node.clone(),
+            _ => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        };
+        state.require_parent_dir(to)?;
+        if matches!(state.nodes.get(to), Some(Node::Dir { .. })) {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        }
+        if let Node::File { ino, .. } = &mut node {
+            *ino = state.next_ino();
+        }
+        let len = node.metadata().len;
+        state.nodes.insert(to.to_path_buf(), node);
+        Ok(len)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let state = self.lock();
+        state.check(FsOp::Open, path)?;
+        match state.node(&state.resolve(path, true)?)? {
+            Node::File { contents, .. } => Ok(Box::new(io::Cursor::new(contents.clone()))),
+            _ => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        }
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::HardLink, original)?;
+        let node = match state.node(original)? {
+            node @ Node::File { .. } => node.clone(),
+            _ => return Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        };
+        state.require_parent_dir(link)?;
+        if state.nodes.contains_key(link) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        state.nodes.insert(link.to_path_buf(), node);
+        Ok(())
+    }
+
+    /// Sets the time on every hardlink to the file.
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check(FsOp::SetModified, path)?;
+        let resolved = state.resolve(path, true)?;
+        let Node::File { ino, .. } = state.node(&resolved)? else {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        };
+        let links: Vec<PathBuf> = state.files_with_ino(*ino).cloned().collect();
+        for link in links {
+            if let Some(Node::File { modified: m, .. }) = state.nodes.get_mut(&link) {
+                *m = modified;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every path shares one filesystem, regardless of mounts.
+    fn disk_usage(&self, path: &Path) -> io::Result<DiskUsage> {
+        let state = self.lock();
+        state.node(path)?;
+        let Some(total_bytes) = state.capacity else {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        };
+        let mut seen = HashSet::new();
+        let used: u64 = state
+            .nodes
+            .values()
+            .filter_map(|node| match node {
+                Node::File { len, ino, .. } if seen.insert(*ino) => Some(*len),
+                _ => None,
+            })
+            .sum();
+        Ok(DiskUsage {
+            total_bytes,
+            available_bytes: total_bytes.saturating_sub(used),
+        })
+    }
+
+    /// `state_dir` is not used: locks live in memory.
+    fn lock_dir(&self, _state_dir: &Path, dir: &Path, wait: bool) -> crate::Result<Box<dyn Any>> {
+        loop {
+            if self.lock().locked.insert(dir.to_path_buf()) {
+                return Ok(Box::new(InMemoryLock {
+                    state: Arc::clone(&self.state),
+                    dir: dir.to_path_buf(),
+                }));
+            }
+            if !wait {
+                return Err(LockedError {
+                    path: dir.to_path_buf(),
+                    pid: None,
+                }
+                .into());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_in_memory_rename_moves_subtree() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let fs = InMemoryFs::new();
+        fs.add_file("/w/dir/a.txt", "abc", t)
+            .add_dir("/w/archive", t);
+
+        fs.rename(Path::new("/w/dir"), Path::new("/w/archive/dir"))
+            .unwrap();
+
+        assert_eq!(fs.contents("/w/archive/dir/a.txt"), Some(b"abc".to_vec()));
+        assert!(!fs.exists(Path::new("/w/dir")));
+        assert_eq!(
+            fs.metadata(Path::new("/w/archive/dir/a.txt"))
+                .unwrap()
+                .modified,
+            t
+        );
+        assert_eq!(
+            fs.read_dir(Path::new("/w")).unwrap(),
+            vec![PathBuf::from("/w/archive")]
+        );
+    }
+
+    #[test]
+    fn test_in_memory_errors() {
+        let t = SystemTime::UNIX_EPOCH;
+        let fs = InMemoryFs::new();
+        fs.add_file("/w/a", "", t)
+            .fail(FsOp::RemoveFile, "/w/a", io::ErrorKind::PermissionDenied);
+
+        let err = fs.remove_file(Path::new("/w/a")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(fs.exists(Path::new("/w/a")));
+        assert_eq!(
+            fs.remove_dir_all(Path::new("/w/a")).unwrap_err().kind(),
+            io::ErrorKind::NotADirectory
+        );
+        assert_eq!(
+            fs.metadata(Path::new("/w/b")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            fs.rename(Path::new("/w/a"), Path::new("/missing/a"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_in_memory_symlinks() {
+        let t = SystemTime::UNIX_EPOCH;
+        let fs = InMemoryFs::new();
+        fs.add_file("/data/a.txt", "abc", t)
+            .add_symlink("/w/data", "../data", t)
+            .add_symlink("/w/ping", "pong", t)
+            .add_symlink("/w/pong", "ping", t);
+
+        assert!(fs.metadata(Path::new("/w/data")).unwrap().is_symlink);
+        assert!(fs.stat(Path::new("/w/data")).unwrap().is_dir);
+        assert_eq!(
+            fs.read_dir(Path::new("/w/data")).unwrap(),
+            vec![PathBuf::from("/w/data/a.txt")]
+        );
+        assert_eq!(fs.metadata(Path::new("/w/data/a.txt")).unwrap().len, 3);
+        assert_eq!(
+            fs.canonicalize(Path::new("/w/data/a.txt")).unwrap(),
+            PathBuf::from("/data/a.txt")
+        );
+        assert_eq!(
+            fs.stat(Path::new("/w/ping")).unwrap_err().raw_os_error(),
+            Some(libc::ELOOP)
+        );
+
+        fs.remove_dir_all(Path::new("/w/data")).unwrap();
+        assert_eq!(
+            fs.contents("/data/a.txt"),
+            Some(b"abc".to_vec()),
+            "only the link is removed"
+        );
+    }
+
+    #[test]
+    fn test_in_memory_lock_is_exclusive_until_dropped() {
+        let fs = InMemoryFs::new();
+        let held = fs
+            .lock_dir(Path::new("/state"), Path::new("/w"), false)
+            .unwrap();
+        assert!(matches!(
+            fs.lock_dir(Path::new("/state"), Path::new("/w"), false),
+            Err(crate::Error::Lock(_))
+        ));
+        assert!(
+            fs.lock_dir(Path::new("/state"), Path::new("/v"), false)
+                .is_ok()
+        );
+        drop(held);
+        assert!(
+            fs.lock_dir(Path::new("/state"), Path::new("/w"), false)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Just enough of git's on-disk format to tell which entries belong to a
+//! repository, without running `git`.
+
+use crate::DirEntryWithAge;
+use crate::filesystem::Filesystem;
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Size of an index entry before its path: timestamps, stat fields, object id and flags.
+const INDEX_ENTRY_FIXED_LEN: usize = 62;
+const INDEX_FLAG_EXTENDED: u16 = 0x4000;
+
+/// A git working tree and the paths its index tracks.
+#[derive(Debug)]
+pub struct WorkTree {
+    pub root: PathBuf,
+    /// Tracked paths relative to `root`, along with every directory that holds one.
+    tracked: HashSet<PathBuf>,
+}
+
+impl WorkTree {
+    /// The working tree containing `dir` (or `dir` itself), if any.
+    pub fn enclosing(fs: &dyn Filesystem, dir: &Path) -> io::Result<Option<WorkTree>> {
+        let Some(root) = dir.ancestors().find(|a| fs.exists(&a.join(".git"))) else {
+            return Ok(None);
+        };
+        let index = git_dir(fs, root)?.join("index");
+        let paths = match read(fs, &index) {
+            Ok(bytes) => {
+                parse_index(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let tracked = paths
+            .iter()
+            .flat_map(|p| p.ancestors().filter(|a| !a.as_os_str().is_empty()))
+            .map(Path::to_path_buf)
+            .collect();
+        Ok(Some(WorkTree {
+            root: root.to_path_buf(),
+            tracked,
+        }))
+    }
+
+    /// Whether `path` is tracked, or is a directory holding tracked files.
+    pub fn tracks(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root)
+            .is_ok_and(|relative| self.tracked.contains(relative))
+    }
+}
+
+/// The repository directory of the working tree at `root`, following a
+/// `.git` file (`gitdir: ...`) as used by worktrees and submodules.
+fn git_dir(fs: &dyn Filesystem, root: &Path) -> io::Result<PathBuf> {
+    let dot_git = root.join(".git");
+    if fs.is_dir(&dot_git) {
+        return Ok(dot_git);
+    }
+    let contents = String::from_utf8_lossy(&read(fs, &dot_git)?).into_owned();
+    let target = contents.trim().strip_prefix("gitdir:").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a gitdir link", dot_git.display()),
+        )
+    })?;
+    Ok(root.join(target.trim()))
+}
+
+fn read(fs: &dyn Filesystem, path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    fs.open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Paths listed in a git index file (versions 2 to 4).
+fn parse_index(bytes: &[u8]) -> Result<Vec<PathBuf>, String> {
+    let u32_at = |at: usize| -> Result<u32, String> {
+        let b = bytes.get(at..at + 4).ok_or("truncated index")?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if bytes.get(..4) != Some(b"DIRC") {
+        return Err("not a git index".to_string());
+    }
+    let version = u32_at(4)?;
+    if !(2..=4).contains(&version) {
+        return Err(format!("unsupported index version {version}"));
+    }
+
+    let mut paths = Vec::new();
+    let mut previous: Vec<u8> = Vec::new();
+    let mut at = 12;
+    for _ in 0..u32_at(8)? {
+        let entry_start = at;
+        let flags = bytes.get(at + 60..at + 62).ok_or("truncated index")?;
+        let flags = u16::from_be_bytes([flags[0], flags[1]]);
+        at += INDEX_ENTRY_FIXED_LEN;
+        if version >= 3 && flags & INDEX_FLAG_EXTENDED != 0 {
+            at += 2;
+        }
+
+        let path = if version == 4 {
+            let (strip, len) =
+                read_offset_varint(&bytes[at.min(bytes.len())..]).ok_or("truncated index")?;
+            at += len;
+            let suffix_len = bytes[at..]
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or("truncated index")?;
+            let keep = previous
+                .len()
+                .checked_sub(strip as usize)
+                .ok_or("corrupt index path")?;
+            let mut path = previous[..keep].to_vec();
+            path.extend_from_slice(&bytes[at..at + suffix_len]);
+            at += suffix_len + 1;
+            path
+        } else {
+            let len = bytes
+                .get(at..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or("truncated index")?;
+            let path = bytes[at..at + len].to_vec();
+            // Entries are NUL-padded to a multiple of eight bytes.
+            at = entry_start + (at - entry_start + len + 8) / 8 * 8;
+            path
+        };
+        paths.push(PathBuf::from(String::from_utf8_lossy(&path).into_owned()));
+        previous = path;
+    }
+    Ok(paths)
+}
+
+/// Decode git's offset varint, returning the value and the bytes it used.
+fn read_offset_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut used = 0;
+    let mut byte = *bytes.first()?;
+    let mut value = u64::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        used += 1;
+        byte = *bytes.get(used)?;
+        value = ((value + 1) << 7) | u64::from(byte & 0x7f);
+    }
+    Some((value, used + 1))
+}
+
+/// The latest activity in the repository at `root`: its last HEAD update
+/// (commit, checkout, ...) or the newest modification in its working tree.
+pub fn last_activity(fs: &dyn Filesystem, root: &Path) -> Option<SystemTime> {
+    let git_dir = git_dir(fs, root).ok()?;
+    let head_update = read(fs, &git_dir.join("logs/HEAD"))
+        .ok()
+        .and_then(|log| {
+            String::from_utf8_lossy(&log)
+                .lines()
+                .filter_map(reflog_time)
+                .next_back()
+        })
+        .or_else(|| fs.metadata(&git_dir.join("index")).map(|m| m.modified).ok());
+    let worktree_change = newest_mtime(fs, root);
+    head_update.max(worktree_change)
+}
+
+/// Timestamp of a reflog line: `<old> <new> <name> <email> <seconds> <tz>\t<message>`.
+fn reflog_time(line: &str) -> Option<SystemTime> {
+    let header = line.split('\t').next()?;
+    let mut fields = header.rsplit(' ');
+    let _tz = fields.next()?;
+    let seconds: u64 = fields.next()?.parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Newest modification time of the files below `dir`, skipping `.git`.
+fn newest_mtime(fs: &dyn Filesystem, dir: &Path) -> Option<SystemTime> {
+    fs.read_dir(dir)
+        .ok()?
+        .into_iter()
+        .filter(|path| path.file_name().is_some_and(|name| name != ".git"))
+        .filter_map(|path| {
+            let meta = fs.metadata(&path).ok()?;
+            if meta.is_dir {
+                newest_mtime(fs, &path).max(Some(meta.modified))
+            } else {
+                Some(meta.modified)
+            }
+        })
+        .max()
+}
+
+/// Apply git-aware handling to the entries of `dir`: entries tracked by a
+/// working tree enclosing `dir` are dropped, and repository roots are aged
+/// by [`last_activity`] as of `now`.
+pub fn adjust_entries(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    entries: Vec<DirEntryWithAge>,
+    now: SystemTime,
+) -> Vec<DirEntryWithAge> {
+    let worktree = WorkTree::enclosing(fs, dir)
+        .inspect_err(|e| log::warn!("Cannot read the git index for {}: {}", dir.display(), e))
+        .ok()
+        .flatten();
+
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let tracked = worktree
+                .as_ref()
+                .is_some_and(|w| w.tracks(Path::new(&entry.path)));
+            if tracked {
+                log::info!("Skipping {}: tracked by git", entry.path);
+            }
+            !tracked
+        })
+        .map(|mut entry| {
+            let path = Path::new(&entry.path);
+            if entry.is_dir
+                && fs.exists(&path.join(".git"))
+                && let Some(last) = last_activity(fs, path)
+            {
+                let age = now.duration_since(last).unwrap_or_default().as_secs();
+                log::debug!(
+                    "Aging repository {} by its last activity: {}s",
+                    entry.path,
+                    age
+                );
+                entry.seconds_since_modification = age;
+            }
+            entry
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use std::cell::OnceCell;
+
+    /// Build an index file listing `paths`, as git would write it.
+    fn index(version: u32, paths: &[&str]) -> Vec<u8> {
+        let mut bytes = b"DIRC".to_vec();
+        bytes.extend(version.to_be_bytes());
+        bytes.extend((paths.len() as u32).to_be_bytes());
+        let mut previous = "";
+        for path in paths {
+            let start = bytes.len();
+            bytes.extend([0; 60]);
+            bytes.extend((path.len() as u16).to_be_bytes());
+            if version == 4 {
+                let common = previous
+                    .bytes()
+                    .zip(path.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                assert!(
+                    previous.len() - common < 0x80,
+                    "test helper only writes one-byte varints"
+                );
+                bytes.push((previous.len() - common) as u8);
+                bytes.extend(&path.as_bytes()[common..]);
+                bytes.push(0);
+            } else {
+                bytes.extend(path.as_bytes());
+                let len = bytes.len() - start;
+                bytes.extend(vec![0; 8 - len % 8]);
+            }
+            previous = path;
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_parse_index_versions() {
+        let paths = ["README.md", "src/lib.rs", "src/main.rs", "tests/it.rs"];
+        let expected: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+        assert_eq!(parse_index(&index(2, &paths)), Ok(expected.clone()));
+        assert_eq!(parse_index(&index(4, &paths)), Ok(expected));
+        assert!(parse_index(b"DIRC\0\0\0\x09").is_err());
+        assert!(parse_index(&index(2, &paths)[..40]).is_err());
+    }
+
+    #[test]
+    fn test_offset_varint() {
+        assert_eq!(read_offset_varint(&[0x05]), Some((5, 1)));
+        assert_eq!(read_offset_varint(&[0x80, 0x00]), Some((128, 2)));
+        assert_eq!(read_offset_varint(&[0x80]), None);
+    }
+
+    #[test]
+    fn test_reflog_time() {
+        let line = "0000000000000000000000000000000000000000 1111111111111111111111111111111111111111 \
+                    A U Thor <a@example.com> 1710000000 +0100\tcommit (initial): first";
+        assert_eq!(
+            reflog_time(line),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1710000000))
+        );
+    }
+
+    #[test]
+    fn test_adjust_entries_reads_through_the_filesystem() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let fs = InMemoryFs::new();
+        fs.add_file("/w/notes.txt", "n", t)
+            .add_file("/w/scratch.txt", "s", t)
+            .add_file("/w/.git/index", index(2, &["notes.txt"]), t)
+            .add_file("/w/repo/.git/index", index(2, &[]), t)
+            .add_file(
+                "/w/repo/src/lib.rs",
+                "fn main() {}",
+                t + Duration::from_secs(3600),
+            );
+        let entry = |path: &str| DirEntryWithAge {
+            path: path.to_string(),
+            seconds_since_modification: 7200,
+            is_dir: path.ends_with("repo"),
+            size_bytes: 0,
+            content_kind: OnceCell::new(),
+        };
+        let entries = vec![entry("/w/notes.txt"), entry("/w/scratch.txt")];
+        let kept = adjust_entries(&fs, Path::new("/w"), entries, t + Duration::from_secs(7200));
+        assert_eq!(
+            kept.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+            ["/w/scratch.txt"]
+        );
+
+        let kept = adjust_entries(
+            &fs,
+            Path::new("/w"),
+            vec![entry("/w/repo")],
+            t + Duration::from_secs(7200),
+        );
+        assert_eq!(
+            kept[0].seconds_since_modification, 3600,
+            "aged by the newest file in the working tree"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::filesystem::Filesystem;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Suffixes browsers and download managers use for files still being written.
+pub const PARTIAL_DOWNLOAD_SUFFIXES: &[&str] = &[".part", ".crdownload", ".download", ".partial"];
+
+pub fn is_partial_download(name: &str) -> bool {
+    PARTIAL_DOWNLOAD_SUFFIXES
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
+
+/// A process holding a file open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    pub pid: u32,
+    pub name: String,
+}
+
+/// Snapshot of which processes hold which files open, taken from `/proc/*/fd`.
+///
+/// Processes whose descriptors we may not read (other users, without root)
+/// are silently left out.
+#[derive(Debug, Default)]
+pub struct OpenFiles {
+    holders: HashMap<(u64, u64), Vec<Holder>>,
+    /// Resolved path of every open descriptor, for looking up whole trees.
+    paths: Vec<(PathBuf, Holder)>,
+}
+
+impl OpenFiles {
+    pub fn scan() -> Self {
+        Self::scan_proc(Path::new("/proc"))
+    }
+
+    fn scan_proc(proc_root: &Path) -> Self {
+        let mut open_files = OpenFiles::default();
+
+        let Ok(processes) = fs::read_dir(proc_root) else {
+            log::debug!(
+                "Cannot read {}, skipping open file scan",
+                proc_root.display()
+            );
+            return open_files;
+        };
+
+        for process in processes.filter_map(Result::ok) {
+            let Some(pid) = process
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+                continue;
+            };
+            let name = fs::read_to_string(process.path().join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+
+            for fd in fds.filter_map(Result::ok) {
+                // metadata() follows the fd symlink to the open file itself
+                let Ok(meta) = fs::metadata(fd.path()) else {
+                    continue;
+                };
+                let holder = Holder {
+                    pid,
+                    name: name.clone(),
+                };
+                let target = fs::read_link(fd.path()).ok();
+                open_files.record(target, meta.dev(), meta.ino(), holder);
+            }
+        }
+
+        open_files
+    }
+
+    /// Note that `holder` has the file identified by `dev` and `ino` open,
+    /// at the fully resolved `path` if known.
+    fn record(&mut self, path: Option<PathBuf>, dev: u64, ino: u64, holder: Holder) {
+        if let Some(path) = path {
+            self.paths.push((path, holder.clone()));
+        }
+        let entry = self.holders.entry((dev, ino)).or_default();
+        if !entry.contains(&holder) {
+            entry.push(holder);
+        }
+    }
+
+    /// Processes holding the file at `path`, looked up through `fs`, open.
+    pub fn holders_of(&self, fs: &dyn Filesystem, path: &Path) -> &[Holder] {
+        fs.metadata(path)
+            .ok()
+            .and_then(|meta| self.holders.get(&(meta.dev, meta.ino)))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// A process holding anything inside the directory `dir` open, or `dir` itself.
+    pub fn holder_under(&self, fs: &dyn Filesystem, dir: &Path) -> Option<&Holder> {
+        // descriptor paths are fully resolved, so compare against the resolved directory
+        let dir = fs.canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        self.paths
+            .iter()
+            .find(|(path, _)| path.starts_with(&dir))
+            .map(|(_, holder)| holder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{InMemoryFs, RealFs};
+    use std::fs::File;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_is_partial_download() {
+        assert!(is_partial_download("ubuntu.iso.part"));
+        assert!(is_partial_download("video.mp4.crdownload"));
+        assert!(is_partial_download("archive.zip.download"));
+        assert!(!is_partial_download("party.txt"));
+    }
+
+    #[test]
+    fn test_scan_finds_own_open_file() {
+        let tmp = TempDir::new().unwrap();
+        let open_path = tmp.path().join("open.txt");
+        let closed_path = tmp.path().join("closed.txt");
+        fs::write(&closed_path, "x").unwrap();
+        let _handle = File::create(&open_path).unwrap();
+
+        let open_files = OpenFiles::scan();
+
+        let holders = open_files.holders_of(&RealFs, &open_path);
+        assert!(holders.iter().any(|h| h.pid == std::process::id()));
+        assert!(open_files.holders_of(&RealFs, &closed_path).is_empty());
+    }
+
+    #[test]
+    fn test_entries_are_resolved_through_the_filesystem() {
+        let t = SystemTime::UNIX_EPOCH;
+        let memory = InMemoryFs::new();
+        memory
+            .add_file("/w/held.log", "x", t)
+            .add_file("/w/idle.txt", "x", t)
+            .add_file("/w/busy/nested/open.txt", "x", t)
+            .add_dir("/w/idle", t)
+            .add_symlink("/w/shortcut", "busy", t);
+        let held = memory.metadata(Path::new("/w/held.log")).unwrap();
+        let open = memory
+            .metadata(Path::new("/w/busy/nested/open.txt"))
+            .unwrap();
+        let holder = |pid| Holder {
+            pid,
+            name: "editor".to_string(),
+        };
+        let mut open_files = OpenFiles::default();
+        open_files.record(None, held.dev, held.ino, holder(1));
+        open_files.record(
+            Some(PathBuf::from("/w/busy/nested/open.txt")),
+            open.dev,
+            open.ino,
+            holder(2),
+        );
+
+        assert_eq!(
+            open_files.holders_of(&memory, Path::new("/w/held.log")),
+            [holder(1)]
+        );
+        assert!(
+            open_files
+                .holders_of(&memory, Path::new("/w/idle.txt"))
+                .is_empty()
+        );
+        assert_eq!(
+            open_files.holder_under(&memory, Path::new("/w/shortcut")),
+            Some(&holder(2))
+        );
+        assert_eq!(open_files.holder_under(&memory, Path::new("/w/idle")), None);
+    }
+
+    #[test]
+    fn test_scan_without_proc_is_empty() {
+        let tmp = TempDir::new().unwrap();
+        let open_files = OpenFiles::scan_proc(&tmp.path().join("missing"));
+        assert!(open_files.holders_of(&RealFs, tmp.path()).is_empty());
+        assert!(open_files.holder_under(&RealFs, tmp.path()).is_none());
+    }
+}
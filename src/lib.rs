@@ -1,18 +1,56 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
-use std::error::Error;
+use std::cell::OnceCell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt;
-use std::fs::{create_dir_all, remove_dir_all, remove_file, rename};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
-const DANGEROUS_PATHS: &[&str] = &[
-    "/",
-    "/usr", "/bin", "/sbin", "/etc", "/var", "/tmp",
-    "/lib", "/opt",
-    "/System", "/Applications",
-];
+pub mod check;
+pub mod clock;
+pub mod config;
+pub mod daemon;
+pub mod dedupe;
+pub mod duration;
+pub mod error;
+pub mod filesystem;
+pub mod git;
+pub mod inuse;
+pub mod limits;
+pub mod lock;
+pub mod magic;
+pub mod mounts;
+pub mod paths;
+pub mod rules;
+pub mod safety;
+pub mod simulate;
+pub mod sort;
+pub mod space;
+pub mod store;
+pub mod systemd;
+pub mod watch;
+
+pub use error::{Error, Result};
+pub use safety::{validate_path_safety, validate_path_safety_with};
+
+use clock::{Clock, FixedClock, SystemClock};
+use dedupe::{DedupeAction, DedupeKeep};
+use duration::Interval;
+use filesystem::{Filesystem, RealFs};
+use magic::ContentKind;
+use rules::{Cutoffs, Rule, RuleAction};
+use sort::{ConflictPolicy, DestTemplate};
+use space::{ByteSize, FreeSpace};
+
+/// Name of the archive directory kept inside each watched directory.
+pub const ARCHIVE_DIR_NAME: &str = ".duansheli-archive";
+
+/// Minimum age of anything deleted or archived early to relieve disk pressure,
+/// so a burst of new downloads cannot push out files that just arrived.
+pub const DEFAULT_PRESSURE_MIN_AGE_HOURS: u64 = 24;
 
 const ALWAYS_IGNORE: &[&str] = &[
     ".DS_Store",
@@ -22,59 +60,242 @@ const ALWAYS_IGNORE: &[&str] = &[
     "desktop.ini",
 ];
 
-#[derive(Debug)]
-pub struct DangerousPathError {
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct DirConfig {
+    /// Directory to declutter; may use `~`, `$VAR` and `xdg:NAME` (see [`DirConfig::expand_path`]).
     pub path: PathBuf,
+    /// `path` as written in the config, set once it has been expanded.
+    #[serde(skip)]
+    pub raw_path: Option<String>,
+    pub time_to_archive_hours: u64,
+    pub time_to_deletion_hours: u64,
+    /// Free-space watermark that triggers early deletion of archive entries.
+    #[serde(default)]
+    pub min_free_space: Option<FreeSpace>,
+    /// Entries younger than this are never touched to relieve disk pressure;
+    /// defaults to [`DEFAULT_PRESSURE_MIN_AGE_HOURS`].
+    #[serde(default)]
+    pub pressure_min_age_hours: Option<u64>,
+    /// Also archive entries below `time_to_archive_hours` while under pressure.
+    #[serde(default)]
+    pub archive_under_pressure: bool,
+    /// Ordered per-entry rules; the first matching rule decides what happens.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Skip the `/proc` scan for files held open by other processes.
+    #[serde(default)]
+    pub ignore_open_files: bool,
+    /// How often `duansheli daemon` processes this directory (default 1h).
+    #[serde(default)]
+    pub interval: Option<Interval>,
+    /// What to do with symlinks found in the directory.
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Also act on entries on another device than the directory, and on
+    /// mount points or directories containing one.
+    #[serde(default)]
+    pub allow_cross_device: bool,
+    /// Extra trees that neither this directory nor `move_to` destinations may lie in.
+    #[serde(default)]
+    pub protected_paths: Vec<PathBuf>,
+    /// Abort a run that would delete more entries than this.
+    #[serde(default)]
+    pub max_deletes_per_run: Option<usize>,
+    /// Abort a run that would delete more bytes than this.
+    #[serde(default)]
+    pub max_bytes_deleted_per_run: Option<ByteSize>,
+    /// Abort a run that would act on more than this share (0 to 1) of the entries.
+    #[serde(default)]
+    pub max_fraction: Option<f64>,
+    /// Leave files tracked by an enclosing git working tree alone, and age
+    /// repositories by their last commit or working-tree change.
+    #[serde(default)]
+    pub git_aware: bool,
+    /// Archive or delete files whose contents duplicate another file in the directory.
+    #[serde(default)]
+    pub dedupe: bool,
+    /// Which copy of a set of duplicates is kept.
+    #[serde(default)]
+    pub dedupe_keep: DedupeKeep,
+    /// Whether the other copies are archived or deleted.
+    #[serde(default)]
+    pub dedupe_action: DedupeAction,
+    /// Hardlink identical archived files to one copy kept in the archive's store.
+    #[serde(default)]
+    pub hardlink_archive: bool,
+    /// Where lock files go instead of [`lock::state_dir`]. Runs only exclude
+    /// each other when they use the same state directory.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
 }
 
-impl fmt::Display for DangerousPathError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "refusing to operate on dangerous path: {}", self.path.display())
-    }
+/// How symlinks in a watched directory are treated.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Leave symlinks alone.
+    Skip,
+    /// Age the link by its own modification time and move or remove only the link.
+    #[default]
+    TreatAsLink,
+    /// Age and size the link by its target, directories included. Only the
+    /// link itself is ever moved or removed; targets are never touched.
+    Follow,
 }
 
-impl std::error::Error for DangerousPathError {}
-
-pub fn validate_path_safety(path: &Path) -> Result<(), DangerousPathError> {
-    let resolved = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-    let candidates = [path, resolved.as_path()];
+/// What the planner decided to do with a root entry.
+#[derive(Debug, PartialEq)]
+enum Decision {
+    /// Explicitly kept by a rule.
+    Keep,
+    /// Below its archive cutoff; left alone unless under disk pressure.
+    Young,
+    Archive,
+    Delete,
+    MoveTo(DestTemplate, ConflictPolicy),
+}
 
-    if let Some(home) = std::env::var_os("HOME") {
-        let home_path = Path::new(&home);
-        let home_resolved = std::fs::canonicalize(home_path)
-            .unwrap_or_else(|_| home_path.to_path_buf());
-        for candidate in &candidates {
-            if *candidate == home_path || *candidate == home_resolved {
-                return Err(DangerousPathError { path: resolved });
-            }
+impl DirConfig {
+    /// Expand `~`, environment variables and XDG user directories in `path`,
+    /// `protected_paths`, `state_dir` and `move_to` destinations, keeping the original
+    /// `path` in `raw_path`. Already expanded paths are left alone.
+    pub fn expand_path(&mut self) -> Result<()> {
+        if self.raw_path.is_some() {
+            return Ok(());
+        }
+        let expand = |path: &Path| {
+            let raw = path.to_string_lossy();
+            paths::expand_path(&raw).map_err(|e| Error::Config(format!("{raw}: {e}")))
+        };
+        for protected in &mut self.protected_paths {
+            *protected = expand(protected)?;
+        }
+        if let Some(state_dir) = &mut self.state_dir {
+            *state_dir = expand(state_dir)?;
         }
+        for dest in self
+            .rules
+            .iter_mut()
+            .filter_map(|rule| rule.move_to.as_mut())
+        {
+            *dest = dest
+                .expand_path()
+                .map_err(|e| Error::Config(format!("move_to {dest}: {e}")))?;
+        }
+        let raw = self.path.to_string_lossy().into_owned();
+        self.path = expand(&self.path)?;
+        self.raw_path = Some(raw);
+        Ok(())
     }
 
-    for &dangerous in DANGEROUS_PATHS {
-        let dangerous_path = Path::new(dangerous);
-        for candidate in &candidates {
-            if *candidate == dangerous_path {
-                return Err(DangerousPathError { path: resolved });
-            }
+    fn default_cutoffs(&self) -> Cutoffs {
+        Cutoffs {
+            archive_secs: self.time_to_archive_hours * 3600,
+            delete_secs: self.time_to_deletion_hours * 3600,
         }
     }
 
-    Ok(())
-}
+    /// Whether planning compares entry sizes: for size conditions or to relieve disk pressure.
+    fn needs_dir_sizes(&self) -> bool {
+        self.min_free_space.is_some()
+            || self
+                .rules
+                .iter()
+                .any(|r| r.min_size.is_some() || r.max_size.is_some())
+    }
 
-#[derive(Deserialize, Debug)]
-pub struct DirConfig {
-    pub path: PathBuf,
-    pub time_to_archive_hours: u64,
-    pub time_to_deletion_hours: u64,
+    /// Decide the fate of a root entry, returning the id of the deciding rule.
+    fn decide(&self, fs: &dyn Filesystem, entry: &DirEntryWithAge) -> (Option<String>, Decision) {
+        let name = entry_name(entry);
+        let (rule_id, action, cutoffs) = match rules::first_match(fs, &self.rules, &name, entry) {
+            Some((id, rule)) => (
+                Some(id),
+                rule.action(),
+                rule.cutoffs(self.default_cutoffs()),
+            ),
+            None => (None, RuleAction::Thresholds, self.default_cutoffs()),
+        };
+
+        let decision = match action {
+            RuleAction::Thresholds if entry.seconds_since_modification >= cutoffs.delete_secs => {
+                Decision::Delete
+            }
+            RuleAction::Thresholds if entry.seconds_since_modification >= cutoffs.archive_secs => {
+                Decision::Archive
+            }
+            RuleAction::Thresholds => Decision::Young,
+            RuleAction::Archive => Decision::Archive,
+            RuleAction::Delete => Decision::Delete,
+            RuleAction::Keep => Decision::Keep,
+            RuleAction::MoveTo(dest, policy) => Decision::MoveTo(dest, policy),
+        };
+        (rule_id, decision)
+    }
+
+    /// Deletion cutoff for an entry already in the archive, matched by its original name.
+    fn archived_delete_cutoff(
+        &self,
+        fs: &dyn Filesystem,
+        entry: &DirEntryWithAge,
+    ) -> (Option<String>, u64) {
+        let name = entry_name(entry);
+        let original = original_name(&name);
+        match rules::first_match(fs, &self.rules, original, entry) {
+            Some((id, rule)) if rule.action() == RuleAction::Thresholds => {
+                (Some(id), rule.cutoffs(self.default_cutoffs()).delete_secs)
+            }
+            _ => (None, self.default_cutoffs().delete_secs),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum FileAction {
-    MoveFile { from: PathBuf, to: PathBuf },
-    MoveDir { from: PathBuf, to: PathBuf },
-    DeleteFile { path: PathBuf },
-    DeleteDir { path: PathBuf },
+    MoveFile {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    MoveDir {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    DeleteFile {
+        path: PathBuf,
+    },
+    DeleteDir {
+        path: PathBuf,
+    },
+    /// Sort an entry into a directory outside the archive.
+    MoveTo {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Archive a file whose contents are identical to `original`'s.
+    ArchiveDuplicate {
+        from: PathBuf,
+        to: PathBuf,
+        original: PathBuf,
+    },
+    /// Delete a file whose contents are identical to `original`'s.
+    DeleteDuplicate {
+        path: PathBuf,
+        original: PathBuf,
+    },
+}
+
+impl FileAction {
+    /// The entry this action moves or deletes.
+    pub fn source(&self) -> &Path {
+        match self {
+            FileAction::MoveFile { from, .. }
+            | FileAction::MoveDir { from, .. }
+            | FileAction::MoveTo { from, .. }
+            | FileAction::ArchiveDuplicate { from, .. } => from,
+            FileAction::DeleteFile { path }
+            | FileAction::DeleteDir { path }
+            | FileAction::DeleteDuplicate { path, .. } => path,
+        }
+    }
 }
 
 impl fmt::Display for FileAction {
@@ -88,6 +309,48 @@ impl fmt::Display for FileAction {
             }
             FileAction::DeleteFile { path } => write!(f, "delete file {}", path.display()),
             FileAction::DeleteDir { path } => write!(f, "delete dir {}", path.display()),
+            FileAction::MoveTo { from, to } => {
+                write!(f, "sort {} -> {}", from.display(), to.display())
+            }
+            FileAction::ArchiveDuplicate { from, to, original } => {
+                write!(
+                    f,
+                    "archive duplicate {} -> {} (same as {})",
+                    from.display(),
+                    to.display(),
+                    original.display()
+                )
+            }
+            FileAction::DeleteDuplicate { path, original } => {
+                write!(
+                    f,
+                    "delete duplicate {} (same as {})",
+                    path.display(),
+                    original.display()
+                )
+            }
+        }
+    }
+}
+
+/// A planned action along with the id of the rule that produced it, if any.
+#[derive(Debug, PartialEq)]
+pub struct PlannedAction {
+    pub action: FileAction,
+    pub rule: Option<String>,
+}
+
+impl PlannedAction {
+    fn untagged(action: FileAction) -> Self {
+        PlannedAction { action, rule: None }
+    }
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.rule {
+            Some(id) => write!(f, "{} (rule {})", self.action, id),
+            None => write!(f, "{}", self.action),
         }
     }
 }
@@ -96,39 +359,88 @@ pub struct DirEntryWithAge {
     pub path: String,
     pub seconds_since_modification: u64,
     pub is_dir: bool,
+    /// Size in bytes. Directories start at 0 and are only summed recursively
+    /// by [`fill_dir_sizes`], since crawling them can be expensive.
+    pub size_bytes: u64,
+    /// Sniffed content type; only read from disk when a rule asks for it.
+    pub content_kind: OnceCell<Option<ContentKind>>,
+}
+
+impl DirEntryWithAge {
+    /// Content type detected from the file's magic bytes, `None` for directories.
+    pub fn kind(&self, fs: &dyn Filesystem) -> Option<ContentKind> {
+        *self.content_kind.get_or_init(|| {
+            if self.is_dir {
+                return None;
+            }
+            magic::detect_file(fs, Path::new(&self.path))
+                .inspect_err(|e| log::warn!("Error sniffing content of {}: {}", self.path, e))
+                .ok()
+                .flatten()
+        })
+    }
+}
+
+fn entry_name(entry: &DirEntryWithAge) -> String {
+    Path::new(&entry.path)
+        .file_name()
+        .expect("entry should have a filename")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Strip the `.{timestamp}.bak` suffix added when an entry was archived.
+fn original_name(archived_name: &str) -> &str {
+    archived_name
+        .strip_suffix(".bak")
+        .and_then(|rest| rest.rsplit_once('.'))
+        .map_or(archived_name, |(original, _timestamp)| original)
+}
+
+fn archive_timestamp(now: DateTime<Utc>) -> String {
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn move_action(entry: DirEntryWithAge, target: PathBuf) -> FileAction {
+    let source = PathBuf::from(entry.path);
+    if entry.is_dir {
+        FileAction::MoveDir {
+            from: source,
+            to: target,
+        }
+    } else {
+        FileAction::MoveFile {
+            from: source,
+            to: target,
+        }
+    }
+}
+
+fn archive_action(archive_path: &Path, entry: DirEntryWithAge, timestamp: &str) -> FileAction {
+    let new_name = format!("{}.{}.bak", entry_name(&entry), timestamp);
+    let target = archive_path.join(&new_name);
+    move_action(entry, target)
+}
+
+fn delete_action(entry: DirEntryWithAge) -> FileAction {
+    let path = PathBuf::from(entry.path);
+    if entry.is_dir {
+        FileAction::DeleteDir { path }
+    } else {
+        FileAction::DeleteFile { path }
+    }
 }
 
 fn plan_archive_actions(
     archive_path: &Path,
     entries: Vec<DirEntryWithAge>,
     cutoff_secs: u64,
+    timestamp: &str,
 ) -> Vec<FileAction> {
-    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
-
     entries
         .into_iter()
         .filter(|e| e.seconds_since_modification >= cutoff_secs)
-        .map(|entry| {
-            let source = PathBuf::from(&entry.path);
-            let filename = source
-                .file_name()
-                .expect("entry should have a filename")
-                .to_string_lossy();
-            let new_name = format!("{}.{}.bak", filename, timestamp);
-            let target = archive_path.join(&new_name);
-
-            if entry.is_dir {
-                FileAction::MoveDir {
-                    from: source,
-                    to: target,
-                }
-            } else {
-                FileAction::MoveFile {
-                    from: source,
-                    to: target,
-                }
-            }
-        })
+        .map(|entry| archive_action(archive_path, entry, timestamp))
         .collect()
 }
 
@@ -136,125 +448,575 @@ fn plan_delete_actions(entries: Vec<DirEntryWithAge>, cutoff_secs: u64) -> Vec<F
     entries
         .into_iter()
         .filter(|e| e.seconds_since_modification >= cutoff_secs)
-        .map(|entry| {
-            let path = PathBuf::from(&entry.path);
-            if entry.is_dir {
-                FileAction::DeleteDir { path }
-            } else {
-                FileAction::DeleteFile { path }
-            }
-        })
+        .map(delete_action)
         .collect()
 }
 
-pub fn plan_declutter(cfg: &DirConfig) -> Result<Vec<FileAction>, Box<dyn Error>> {
-    let archive_name = ".duansheli-archive";
-    let archive_path = cfg.path.join(archive_name);
-    let archive_cutoff = cfg.time_to_archive_hours * 3600;
-    let delete_cutoff = cfg.time_to_deletion_hours * 3600;
+/// Bytes that deleting entries actually frees, when some of them are
+/// hardlinks to the same file as in a hardlinked archive.
+struct Reclaim<'a> {
+    fs: &'a dyn Filesystem,
+    archive_path: PathBuf,
+    /// Links to each shared file, by device and inode, not yet planned for deletion.
+    remaining: HashMap<(u64, u64), u64>,
+}
 
-    let root_entries = list_dir_with_meta(&cfg.path, Some(archive_name))?;
+impl<'a> Reclaim<'a> {
+    fn new(fs: &'a dyn Filesystem, archive_path: &Path) -> Self {
+        Reclaim {
+            fs,
+            archive_path: archive_path.to_path_buf(),
+            remaining: HashMap::new(),
+        }
+    }
 
-    let (to_delete, to_archive): (Vec<_>, Vec<_>) = root_entries
+    /// Bytes freed by deleting `entry` after the entries passed before it.
+    /// A shared file is only freed with its last link; in the archive, the
+    /// store's link goes away on its own once no entry is left.
+    fn delete(&mut self, entry: &DirEntryWithAge) -> u64 {
+        let path = Path::new(&entry.path);
+        let meta = match self.fs.metadata(path) {
+            Ok(meta) if !meta.is_dir && meta.nlink > 1 => meta,
+            _ => return entry.size_bytes,
+        };
+        let store_link = u64::from(
+            path.parent() == Some(self.archive_path.as_path())
+                && self
+                    .fs
+                    .is_dir(&self.archive_path.join(store::STORE_DIR_NAME)),
+        );
+        let links = self
+            .remaining
+            .entry((meta.dev, meta.ino))
+            .or_insert(meta.nlink - store_link);
+        *links = links.saturating_sub(1);
+        if *links == 0 { entry.size_bytes } else { 0 }
+    }
+}
+
+/// Pick the oldest entries until `deficit` bytes are covered, counting what
+/// each frees with `freed_by`.
+fn select_oldest_until(
+    entries: Vec<DirEntryWithAge>,
+    min_age_secs: u64,
+    deficit: &mut u64,
+    mut freed_by: impl FnMut(&DirEntryWithAge) -> u64,
+) -> Vec<DirEntryWithAge> {
+    let mut candidates: Vec<_> = entries
         .into_iter()
-        .filter(|e| e.seconds_since_modification >= archive_cutoff)
-        .partition(|e| e.seconds_since_modification >= delete_cutoff);
+        .filter(|e| e.seconds_since_modification >= min_age_secs)
+        .collect();
+    candidates.sort_by_key(|e| Reverse(e.seconds_since_modification));
+
+    let mut selected = Vec::new();
+    for entry in candidates {
+        if *deficit == 0 {
+            break;
+        }
+        *deficit = deficit.saturating_sub(freed_by(&entry));
+        selected.push(entry);
+    }
+    selected
+}
+
+/// Plan extra actions to relieve disk pressure once regular cutoffs are applied.
+///
+/// Archive entries are deleted oldest-first until `deficit` bytes are freed.
+/// If that is not enough and `archive_young` is set, young root entries are
+/// archived early (oldest-first) so later runs can reclaim them.
+fn plan_pressure_actions(
+    cfg: &DirConfig,
+    reclaim: &mut Reclaim,
+    archived: Vec<DirEntryWithAge>,
+    young: Vec<DirEntryWithAge>,
+    mut deficit: u64,
+    timestamp: &str,
+) -> Vec<FileAction> {
+    let min_age_secs = cfg
+        .pressure_min_age_hours
+        .unwrap_or(DEFAULT_PRESSURE_MIN_AGE_HOURS)
+        * 3600;
+    let reclaimed =
+        select_oldest_until(archived, min_age_secs, &mut deficit, |e| reclaim.delete(e));
+    let mut actions = plan_delete_actions(reclaimed, 0);
+
+    if deficit > 0 && cfg.archive_under_pressure {
+        let early = select_oldest_until(young, min_age_secs, &mut deficit, |e| e.size_bytes);
+        actions.extend(plan_archive_actions(
+            &cfg.path.join(ARCHIVE_DIR_NAME),
+            early,
+            0,
+            timestamp,
+        ));
+    }
+
+    if deficit > 0 {
+        log::warn!("Free-space watermark still short by {} bytes", deficit);
+    }
+
+    actions
+}
+
+/// Plan moving `entry` into the directory given by `dest`.
+///
+/// The destination gets the same safety check as the watched directory
+/// itself. Returns `None` if the entry is skipped due to a name conflict.
+fn plan_sort_action(
+    fs: &dyn Filesystem,
+    cfg: &DirConfig,
+    entry: DirEntryWithAge,
+    dest: &DestTemplate,
+    policy: ConflictPolicy,
+    claimed: &mut HashSet<PathBuf>,
+    now: SystemTime,
+) -> Result<Option<FileAction>> {
+    let name = entry_name(&entry);
+    let modified = now
+        .checked_sub(Duration::from_secs(entry.seconds_since_modification))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let dest_dir = dest.expand(&name, modified.into());
+    let dest_dir = if dest_dir.is_relative() {
+        cfg.path.join(dest_dir)
+    } else {
+        dest_dir
+    };
+
+    validate_path_safety_with(&dest_dir, &cfg.protected_paths)?;
+    if dest_dir.starts_with(&entry.path) {
+        return Err(Error::Config(format!(
+            "refusing to move {} into itself ({})",
+            entry.path,
+            dest_dir.display()
+        )));
+    }
+
+    let Some(target) = sort::resolve_target(fs, &dest_dir, &name, policy, claimed) else {
+        log::info!(
+            "Skipping {}: {} already exists in {}",
+            entry.path,
+            name,
+            dest_dir.display()
+        );
+        return Ok(None);
+    };
+
+    Ok(Some(FileAction::MoveTo {
+        from: PathBuf::from(entry.path),
+        to: target,
+    }))
+}
+
+/// Archive or delete duplicated files among `entries`, removing them from
+/// `entries` so they are not planned twice.
+///
+/// A set is left alone when its kept copy is due for deletion anyway, so the
+/// content never disappears earlier than the thresholds say; copies that a
+/// rule keeps are left alone too.
+fn plan_dedupe_actions(
+    fs: &dyn Filesystem,
+    cfg: &DirConfig,
+    entries: &mut Vec<DirEntryWithAge>,
+    archive_path: &Path,
+    timestamp: &str,
+) -> Vec<PlannedAction> {
+    let mut duplicates = HashSet::new();
+    let mut actions = Vec::new();
+    for group in dedupe::find_duplicates(fs, entries, cfg.dedupe_keep) {
+        let decision = |path: &Path| {
+            let entry = entries
+                .iter()
+                .find(|e| Path::new(&e.path) == path)
+                .expect("duplicates come from entries");
+            cfg.decide(fs, entry).1
+        };
+        if decision(&group.kept) == Decision::Delete {
+            log::debug!(
+                "Not deduplicating {}: it is due for deletion",
+                group.kept.display()
+            );
+            continue;
+        }
+        for copy in group.copies {
+            if decision(&copy) == Decision::Keep {
+                log::debug!("Keeping duplicate {}: kept by a rule", copy.display());
+                continue;
+            }
+            let action = match cfg.dedupe_action {
+                DedupeAction::Archive => {
+                    let name = copy.file_name().unwrap_or_default().to_string_lossy();
+                    FileAction::ArchiveDuplicate {
+                        to: archive_path.join(format!("{name}.{timestamp}.bak")),
+                        from: copy.clone(),
+                        original: group.kept.clone(),
+                    }
+                }
+                DedupeAction::Delete => FileAction::DeleteDuplicate {
+                    path: copy.clone(),
+                    original: group.kept.clone(),
+                },
+            };
+            actions.push(PlannedAction::untagged(action));
+            duplicates.insert(copy);
+        }
+    }
+    entries.retain(|e| !duplicates.contains(Path::new(&e.path)));
+    actions
+}
+
+/// Plan what to do with `cfg`'s directory, judging every age against one reading of `clock`.
+pub fn plan_declutter(
+    fs: &dyn Filesystem,
+    clock: &dyn Clock,
+    cfg: &DirConfig,
+) -> Result<Vec<PlannedAction>> {
+    let now = clock.now();
+    let archive_path = cfg.path.join(ARCHIVE_DIR_NAME);
+    let timestamp = archive_timestamp(now);
+
+    let mut root_entries = list_dir_with_meta(
+        fs,
+        &cfg.path,
+        Some(ARCHIVE_DIR_NAME),
+        now.into(),
+        cfg.symlinks,
+    )?;
+    let mut archive_entries = list_dir_with_meta(
+        fs,
+        &archive_path,
+        Some(store::STORE_DIR_NAME),
+        now.into(),
+        cfg.symlinks,
+    )?;
+    archive_entries.retain(|entry| !store::is_temp_link(Path::new(&entry.path)));
+    if !cfg.allow_cross_device {
+        root_entries = mounts::skip_other_filesystems(fs, &cfg.path, root_entries)?;
+        archive_entries = mounts::skip_other_filesystems(fs, &archive_path, archive_entries)?;
+    }
+    root_entries.retain(|entry| {
+        let marked = entry.is_dir && safety::is_marked(fs, Path::new(&entry.path));
+        if marked {
+            log::info!(
+                "Skipping {}: protected by {}",
+                entry.path,
+                safety::PROTECT_MARKER
+            );
+        }
+        !marked
+    });
+    if cfg.git_aware {
+        root_entries = git::adjust_entries(fs, &cfg.path, root_entries, now.into());
+    }
+    // Only after skipping mounts and marked directories, so sizing never crawls them.
+    if cfg.needs_dir_sizes() {
+        fill_dir_sizes(fs, &mut root_entries, cfg.symlinks);
+        fill_dir_sizes(fs, &mut archive_entries, cfg.symlinks);
+    }
 
-    let mut actions = plan_delete_actions(to_delete, delete_cutoff);
-    actions.extend(plan_archive_actions(&archive_path, to_archive, archive_cutoff));
+    let mut actions = if cfg.dedupe {
+        plan_dedupe_actions(fs, cfg, &mut root_entries, &archive_path, &timestamp)
+    } else {
+        Vec::new()
+    };
+    let mut young = Vec::new();
+    let mut retained = Vec::new();
+    let mut claimed = HashSet::new();
+    let mut reclaim = Reclaim::new(fs, &archive_path);
+    let mut freed = 0;
+
+    for entry in root_entries {
+        let (rule, decision) = cfg.decide(fs, &entry);
+        let action = match decision {
+            Decision::Delete => {
+                freed += reclaim.delete(&entry);
+                delete_action(entry)
+            }
+            Decision::Archive => archive_action(&archive_path, entry, &timestamp),
+            Decision::MoveTo(dest, policy) => {
+                match plan_sort_action(fs, cfg, entry, &dest, policy, &mut claimed, now.into())? {
+                    Some(action) => action,
+                    None => continue,
+                }
+            }
+            Decision::Young => {
+                young.push(entry);
+                continue;
+            }
+            Decision::Keep => {
+                log::debug!("Keeping {} (rule {})", entry.path, rule.unwrap_or_default());
+                continue;
+            }
+        };
+        actions.push(PlannedAction { action, rule });
+    }
 
     // Delete existing archive entries that exceed deletion cutoff
-    let archive_entries = list_dir_with_meta(&archive_path, None)?;
-    actions.extend(plan_delete_actions(archive_entries, delete_cutoff));
+    for entry in archive_entries {
+        let (rule, delete_cutoff) = cfg.archived_delete_cutoff(fs, &entry);
+        if entry.seconds_since_modification >= delete_cutoff {
+            freed += reclaim.delete(&entry);
+            actions.push(PlannedAction {
+                action: delete_action(entry),
+                rule,
+            });
+        } else {
+            retained.push(entry);
+        }
+    }
+
+    if let Some(watermark) = cfg.min_free_space {
+        let usage = fs
+            .disk_usage(&cfg.path)
+            .map_err(Error::io("check free space on", &cfg.path))?;
+        let deficit = usage.deficit(watermark).saturating_sub(freed);
+        if deficit > 0 {
+            log::info!(
+                "Below free-space watermark {} on {}, need {} more bytes",
+                watermark,
+                cfg.path.display(),
+                deficit
+            );
+            let pressure =
+                plan_pressure_actions(cfg, &mut reclaim, retained, young, deficit, &timestamp);
+            actions.extend(pressure.into_iter().map(PlannedAction::untagged));
+        }
+    }
 
     Ok(actions)
 }
 
-pub fn execute_actions(actions: &[FileAction]) -> Result<(), Box<dyn Error>> {
-    for action in actions {
-        match action {
-            FileAction::MoveFile { from, to } | FileAction::MoveDir { from, to } => {
+/// Drop actions on files that are still being downloaded or held open, and
+/// on directories with anything inside them held open.
+///
+/// Partial-download suffixes are always honoured; the `/proc` scan for open
+/// handles runs once per call unless `check_open` is false.
+pub fn skip_busy_files(
+    fs: &dyn Filesystem,
+    actions: Vec<PlannedAction>,
+    check_open: bool,
+) -> Vec<PlannedAction> {
+    let open_files = check_open.then(inuse::OpenFiles::scan);
+
+    actions
+        .into_iter()
+        .filter(|planned| {
+            let path = planned.action.source();
+            let is_dir = fs.is_dir(path);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !is_dir && inuse::is_partial_download(&name) {
+                log::info!("Skipping {}: partial download", path.display());
+                return false;
+            }
+            let holder = open_files.as_ref().and_then(|o| {
+                if is_dir {
+                    o.holder_under(fs, path)
+                } else {
+                    o.holders_of(fs, path).first()
+                }
+            });
+            if let Some(holder) = holder {
+                log::info!(
+                    "Skipping {}: open in process {} ({})",
+                    path.display(),
+                    holder.pid,
+                    holder.name
+                );
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+/// Execute `actions` in order, stopping early once `cancel` is set.
+pub fn execute_actions(
+    fs: &dyn Filesystem,
+    actions: &[PlannedAction],
+    cancel: Option<&AtomicBool>,
+) -> Result<()> {
+    for (done, planned) in actions.iter().enumerate() {
+        if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+            log::info!("Stopping after {} of {} actions", done, actions.len());
+            break;
+        }
+        match &planned.action {
+            FileAction::MoveFile { from, to }
+            | FileAction::MoveDir { from, to }
+            | FileAction::ArchiveDuplicate { from, to, .. } => {
                 log::info!("Moving {} -> {}", from.display(), to.display());
-                rename(from, to)?;
+                fs.rename(from, to).map_err(Error::io("move", from))?;
+            }
+            FileAction::MoveTo { from, to } => {
+                log::info!("Sorting {} -> {}", from.display(), to.display());
+                sort::move_entry(fs, from, to).map_err(Error::io("sort", from))?;
             }
             FileAction::DeleteFile { path } => {
                 log::info!("Removing file {}", path.display());
-                remove_file(path)?;
+                fs.remove_file(path).map_err(Error::io("remove", path))?;
+            }
+            FileAction::DeleteDuplicate { path, original } => {
+                log::info!(
+                    "Removing {}, a duplicate of {}",
+                    path.display(),
+                    original.display()
+                );
+                fs.remove_file(path).map_err(Error::io("remove", path))?;
+            }
+            FileAction::DeleteDir { path } if fs.metadata(path).is_ok_and(|m| m.is_symlink) => {
+                log::info!("Removing link {}, leaving its target alone", path.display());
+                fs.remove_file(path).map_err(Error::io("remove", path))?;
             }
             FileAction::DeleteDir { path } => {
                 log::info!("Removing dir {} and all its contents", path.display());
-                remove_dir_all(path)?;
+                fs.remove_dir_all(path).map_err(Error::io("remove", path))?;
             }
         }
     }
     Ok(())
 }
 
-pub fn declutter_directory(cfg: DirConfig, dry_run: bool) -> Result<(), Box<dyn Error>> {
-    validate_path_safety(&cfg.path)?;
+/// Options for a single declutter run that come from the command line rather than config.
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    pub dry_run: bool,
+    /// Block until a concurrent run on the same directory finishes instead of failing.
+    pub wait_for_lock: bool,
+    /// Checked between actions; once set, the run stops before the next action.
+    pub cancel: Option<&'static AtomicBool>,
+    /// Plan as if it were this instant instead of reading the system clock.
+    pub now: Option<DateTime<Utc>>,
+    /// Execute plans that exceed the directory's per-run limits.
+    pub force: bool,
+}
+
+pub fn declutter_directory(cfg: DirConfig, dry_run: bool) -> Result<()> {
+    let opts = RunOptions {
+        dry_run,
+        ..Default::default()
+    };
+    declutter_directory_with(cfg, &opts)
+}
+
+pub fn declutter_directory_with(cfg: DirConfig, opts: &RunOptions) -> Result<()> {
+    declutter_directory_in(&RealFs, cfg, opts)
+}
+
+/// Like [`declutter_directory_with`], but planning and acting on `fs`.
+pub fn declutter_directory_in(
+    fs: &dyn Filesystem,
+    cfg: DirConfig,
+    opts: &RunOptions,
+) -> Result<()> {
+    validate_path_safety_with(&cfg.path, &cfg.protected_paths)?;
+    if !fs.is_dir(&cfg.path) {
+        return Err(Error::MissingDirectory { path: cfg.path });
+    }
+    safety::check_protected_dir(fs, &cfg.path)?;
 
-    let archive_path = cfg.path.join(".duansheli-archive");
-    create_dir_all(&archive_path)?;
+    let archive_path = cfg.path.join(ARCHIVE_DIR_NAME);
+    fs.create_dir_all(&archive_path)
+        .map_err(Error::io("create archive", &archive_path))?;
+    let state_dir = match &cfg.state_dir {
+        Some(state_dir) => state_dir.clone(),
+        None => lock::state_dir()?,
+    };
+    let _lock = fs.lock_dir(&state_dir, &cfg.path, opts.wait_for_lock)?;
 
-    let actions = plan_declutter(&cfg)?;
+    let clock: &dyn Clock = match opts.now {
+        Some(now) => &FixedClock(now),
+        None => &SystemClock,
+    };
+    let actions = plan_declutter(fs, clock, &cfg)?;
+    let actions = skip_busy_files(fs, actions, !cfg.ignore_open_files);
+    let within_limits = if opts.force {
+        Ok(())
+    } else {
+        limits::check_run_limits(fs, &cfg, &actions)
+    };
 
-    if dry_run {
+    if opts.dry_run {
         for action in &actions {
             println!("[dry-run] {}", action);
         }
+        within_limits
     } else {
-        execute_actions(&actions)?;
+        within_limits?;
+        execute_actions(fs, &actions, opts.cancel)?;
+        if cfg.hardlink_archive {
+            store::link_archive(fs, &archive_path)?;
+        }
+        store::collect_garbage(fs, &archive_path)
     }
-
-    Ok(())
 }
 
+/// List `dir`'s entries with their age as of `now`; entries modified after `now` are skipped.
+///
+/// Directory sizes are left at 0; see [`fill_dir_sizes`].
 pub fn list_dir_with_meta(
+    fs: &dyn Filesystem,
     dir: &Path,
     exclude_recursive: Option<&str>,
-) -> Result<Vec<DirEntryWithAge>, Box<dyn Error>> {
-    if !dir.is_dir() {
-        let err = Err("Directory does not exist".into());
-        return err;
+    now: SystemTime,
+    symlinks: SymlinkPolicy,
+) -> Result<Vec<DirEntryWithAge>> {
+    if !fs.is_dir(dir) {
+        return Err(Error::MissingDirectory {
+            path: dir.to_path_buf(),
+        });
     }
 
-    let entries: Vec<DirEntryWithAge> = dir
-        .read_dir()?
-        .filter_map(|entry_result| {
-            let entry = entry_result
-                .inspect_err(|e| log::warn!("Error reading entry: {}", e))
-                .ok()?;
+    let entries: Vec<DirEntryWithAge> = fs
+        .read_dir(dir)
+        .map_err(Error::io("read directory", dir))?
+        .into_iter()
+        .filter_map(|path| {
+            let file_name = path.file_name()?;
 
-            if exclude_recursive.is_some_and(|x| x == entry.file_name()) {
-                log::debug!("Excluding: {:?}", entry.path());
+            if exclude_recursive.is_some_and(|x| x == file_name) {
+                log::debug!("Excluding: {:?}", path);
                 return None;
             }
 
-            if ALWAYS_IGNORE.iter().any(|&ignored| OsStr::new(ignored) == entry.file_name()) {
-                log::debug!("Ignoring metadata file: {:?}", entry.path());
+            if ALWAYS_IGNORE
+                .iter()
+                .any(|&ignored| OsStr::new(ignored) == file_name)
+            {
+                log::debug!("Ignoring metadata file: {:?}", path);
                 return None;
             }
 
-            let meta = entry
-                .metadata()
+            let mut meta = fs
+                .metadata(&path)
                 .inspect_err(|e| log::warn!("Error reading metadata: {}", e))
                 .ok()?;
 
-            let modified = meta
-                .modified()
-                .inspect_err(|e| log::warn!("Error getting modification date: {}", e))
-                .ok()?;
+            if meta.is_symlink {
+                match symlinks {
+                    SymlinkPolicy::Skip => {
+                        log::debug!("Skipping symlink: {:?}", path);
+                        return None;
+                    }
+                    SymlinkPolicy::TreatAsLink => {}
+                    SymlinkPolicy::Follow => match fs.stat(&path) {
+                        Ok(target) => meta = target,
+                        Err(e) => {
+                            log::warn!("Treating {:?} as a link, cannot follow it: {}", path, e)
+                        }
+                    },
+                }
+            }
 
-            let seconds_since_modification = SystemTime::now()
-                .duration_since(modified)
+            let seconds_since_modification = now
+                .duration_since(meta.modified)
                 .inspect_err(|e| log::warn!("Error getting time since modification: {}", e))
                 .ok()?
                 .as_secs();
 
             Some(DirEntryWithAge {
-                path: entry.path().to_string_lossy().into_owned(),
+                path: path.to_string_lossy().into_owned(),
                 seconds_since_modification,
-                is_dir: meta.is_dir(),
+                is_dir: meta.is_dir,
+                size_bytes: if meta.is_dir { 0 } else { meta.len },
+                content_kind: OnceCell::new(),
             })
         })
         .collect();
@@ -262,16 +1024,78 @@ pub fn list_dir_with_meta(
     Ok(entries)
 }
 
+/// Sum the contents of each directory among `entries` into its `size_bytes`.
+pub fn fill_dir_sizes(
+    fs: &dyn Filesystem,
+    entries: &mut [DirEntryWithAge],
+    symlinks: SymlinkPolicy,
+) {
+    let follow = symlinks == SymlinkPolicy::Follow;
+    for entry in entries.iter_mut().filter(|e| e.is_dir) {
+        entry.size_bytes = dir_size(fs, Path::new(&entry.path), follow, &mut HashSet::new());
+    }
+}
+
+/// Recursively sum file sizes below `dir`, following symlinks if `follow` is set.
+///
+/// `visited` holds the directories already counted, so followed links that
+/// loop back or point at the same directory twice are counted once.
+pub(crate) fn dir_size(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    follow: bool,
+    visited: &mut HashSet<PathBuf>,
+) -> u64 {
+    if follow && !visited.insert(fs.canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())) {
+        log::debug!(
+            "Not counting {} again: already visited through a symlink",
+            dir.display()
+        );
+        return 0;
+    }
+    let Ok(entries) = fs.read_dir(dir) else {
+        log::warn!("Error reading directory for size: {}", dir.display());
+        return 0;
+    };
+    entries
+        .iter()
+        .filter_map(|path| {
+            let meta = if follow {
+                fs.stat(path)
+            } else {
+                fs.metadata(path)
+            }
+            .ok()?;
+            Some(if meta.is_dir {
+                dir_size(fs, path, follow, visited)
+            } else {
+                meta.len
+            })
+        })
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::path::PathBuf;
 
     fn make_entry(path: &str, age_secs: u64, is_dir: bool) -> DirEntryWithAge {
+        make_sized_entry(path, age_secs, is_dir, 0)
+    }
+
+    fn make_sized_entry(
+        path: &str,
+        age_secs: u64,
+        is_dir: bool,
+        size_bytes: u64,
+    ) -> DirEntryWithAge {
         DirEntryWithAge {
             path: path.to_string(),
             seconds_since_modification: age_secs,
             is_dir,
+            size_bytes,
+            content_kind: OnceCell::new(),
         }
     }
 
@@ -284,7 +1108,7 @@ mod tests {
             make_entry("/tmp/root/old_dir", 7200, true),
         ];
 
-        let actions = plan_archive_actions(&archive, entries, cutoff);
+        let actions = plan_archive_actions(&archive, entries, cutoff, "20240309T120000Z");
 
         assert_eq!(actions.len(), 2);
         match &actions[0] {
@@ -316,7 +1140,7 @@ mod tests {
             make_entry("/tmp/root/young_dir", 500, true),
         ];
 
-        let actions = plan_archive_actions(&archive, entries, cutoff);
+        let actions = plan_archive_actions(&archive, entries, cutoff, "20240309T120000Z");
         assert!(actions.is_empty());
     }
 
@@ -357,6 +1181,95 @@ mod tests {
         assert!(actions.is_empty());
     }
 
+    const TS: &str = "20240309T120000Z";
+
+    fn pressure_cfg(min_age_hours: u64, archive_under_pressure: bool) -> DirConfig {
+        DirConfig {
+            path: PathBuf::from("/tmp/root"),
+            pressure_min_age_hours: Some(min_age_hours),
+            archive_under_pressure,
+            ..Default::default()
+        }
+    }
+
+    /// Nothing in the planned paths exists, so every entry frees its full size.
+    fn reclaim() -> Reclaim<'static> {
+        static FS: std::sync::LazyLock<filesystem::InMemoryFs> =
+            std::sync::LazyLock::new(filesystem::InMemoryFs::new);
+        Reclaim::new(&*FS, Path::new("/tmp/archive"))
+    }
+
+    #[test]
+    fn test_plan_pressure_actions_deletes_oldest_first() {
+        let archived = vec![
+            make_sized_entry("/tmp/archive/newer.bak", 5000, false, 100),
+            make_sized_entry("/tmp/archive/oldest.bak", 9000, false, 100),
+            make_sized_entry("/tmp/archive/older.bak", 7000, false, 100),
+        ];
+
+        let actions = plan_pressure_actions(
+            &pressure_cfg(0, false),
+            &mut reclaim(),
+            archived,
+            vec![],
+            150,
+            TS,
+        );
+
+        assert_eq!(
+            actions,
+            vec![
+                FileAction::DeleteFile {
+                    path: PathBuf::from("/tmp/archive/oldest.bak")
+                },
+                FileAction::DeleteFile {
+                    path: PathBuf::from("/tmp/archive/older.bak")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_pressure_actions_respects_min_age() {
+        let archived = vec![make_sized_entry("/tmp/archive/fresh.bak", 100, false, 1000)];
+        let young = vec![make_sized_entry("/tmp/root/fresh.txt", 100, false, 1000)];
+
+        let actions = plan_pressure_actions(
+            &pressure_cfg(1, true),
+            &mut reclaim(),
+            archived,
+            young,
+            500,
+            TS,
+        );
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn test_plan_pressure_actions_archives_young_when_enabled() {
+        let young = vec![
+            make_sized_entry("/tmp/root/a.txt", 4000, false, 100),
+            make_sized_entry("/tmp/root/b.txt", 8000, false, 100),
+        ];
+
+        let actions = plan_pressure_actions(
+            &pressure_cfg(1, true),
+            &mut reclaim(),
+            vec![],
+            young,
+            50,
+            TS,
+        );
+
+        assert_eq!(actions.len(), 1);
+        match &actions[0] {
+            FileAction::MoveFile { from, .. } => {
+                assert_eq!(from, &PathBuf::from("/tmp/root/b.txt"))
+            }
+            other => panic!("expected MoveFile, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_validate_path_safety_rejects_root() {
         assert!(validate_path_safety(Path::new("/")).is_err());
@@ -380,6 +1293,79 @@ mod tests {
         assert!(validate_path_safety(&tmp).is_ok());
     }
 
+    #[test]
+    fn test_original_name_strips_archive_suffix() {
+        assert_eq!(
+            original_name("report.pdf.20240101T000000Z.bak"),
+            "report.pdf"
+        );
+        assert_eq!(original_name("DIR.20240101T000000Z.bak"), "DIR");
+        assert_eq!(original_name("manual.bak"), "manual.bak");
+    }
+
+    #[test]
+    fn test_decide_first_matching_rule_wins() {
+        let cfg = DirConfig {
+            path: PathBuf::from("/tmp/root"),
+            time_to_archive_hours: 1,
+            time_to_deletion_hours: 2,
+            rules: vec![
+                toml::from_str("id = \"keep-notes\"\nextension = \"md\"\naction = \"keep\"")
+                    .unwrap(),
+                toml::from_str("glob = \"*.iso\"\nmove_to = \"/srv/isos\"").unwrap(),
+                toml::from_str("is_dir = true\naction = \"delete\"").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let fs = filesystem::InMemoryFs::new();
+
+        let old = 3 * 3600;
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/notes.md", old, false)),
+            (Some("keep-notes".to_string()), Decision::Keep)
+        );
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/os.iso", 0, false)),
+            (
+                Some("#2".to_string()),
+                Decision::MoveTo(
+                    DestTemplate::try_from("/srv/isos".to_string()).unwrap(),
+                    ConflictPolicy::Rename
+                )
+            )
+        );
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/build", 0, true)),
+            (Some("#3".to_string()), Decision::Delete)
+        );
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/a.txt", old, false)),
+            (None, Decision::Delete)
+        );
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/a.txt", 5400, false)),
+            (None, Decision::Archive)
+        );
+        assert_eq!(
+            cfg.decide(&fs, &make_entry("/tmp/root/a.txt", 0, false)),
+            (None, Decision::Young)
+        );
+    }
+
+    #[test]
+    fn test_display_planned_action_names_rule() {
+        let planned = PlannedAction {
+            action: FileAction::DeleteFile {
+                path: PathBuf::from("/x/y.torrent"),
+            },
+            rule: Some("torrents".to_string()),
+        };
+        assert_eq!(
+            format!("{}", planned),
+            "delete file /x/y.torrent (rule torrents)"
+        );
+    }
+
     #[test]
     fn test_display_file_action() {
         let action = FileAction::MoveFile {
@@ -393,4 +1379,428 @@ mod tests {
         };
         assert_eq!(format!("{}", action), "delete dir /x/y");
     }
+
+    fn test_clock() -> FixedClock {
+        FixedClock(clock::parse_timestamp("2024-03-09T12:00:00Z").unwrap())
+    }
+
+    fn hours_ago(hours: u64) -> SystemTime {
+        SystemTime::from(test_clock().now()) - Duration::from_secs(hours * 3600)
+    }
+
+    #[test]
+    fn test_plan_and_execute_on_in_memory_fs() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_dir("/w", hours_ago(0))
+            .add_file("/w/new.txt", "n", hours_ago(0))
+            .add_file("/w/old.txt", "o", hours_ago(30))
+            .add_file("/w/big/inner.bin", vec![0; 100], hours_ago(30))
+            .add_file(
+                format!("/w/{ARCHIVE_DIR_NAME}/ancient.txt.20200101T000000Z.bak"),
+                "a",
+                hours_ago(200),
+            );
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            ..Default::default()
+        };
+
+        let actions = plan_declutter(&fs, &test_clock(), &cfg).unwrap();
+        assert_eq!(actions.len(), 3, "{actions:?}");
+        execute_actions(&fs, &actions, None).unwrap();
+
+        let archived: Vec<_> = fs
+            .read_dir(&cfg.path.join(ARCHIVE_DIR_NAME))
+            .unwrap()
+            .into_iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            archived,
+            ["big.20240309T120000Z.bak", "old.txt.20240309T120000Z.bak"]
+        );
+        assert!(fs.exists(Path::new("/w/new.txt")));
+    }
+
+    #[test]
+    fn test_plan_against_earlier_instant() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/w/new.txt", "n", hours_ago(0))
+            .add_file("/w/old.txt", "o", hours_ago(30))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(100));
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            ..Default::default()
+        };
+        let earlier = FixedClock(test_clock().now() - chrono::Duration::hours(10));
+
+        let entries = list_dir_with_meta(
+            &fs,
+            &cfg.path,
+            Some(ARCHIVE_DIR_NAME),
+            earlier.now().into(),
+            cfg.symlinks,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1, "new.txt did not exist yet");
+        assert_eq!(entries[0].seconds_since_modification, 20 * 3600);
+        assert!(plan_declutter(&fs, &earlier, &cfg).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_execute_reports_failing_path() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/w/a.txt", "a", hours_ago(0))
+            .add_file("/w/b.txt", "b", hours_ago(0))
+            .fail(
+                filesystem::FsOp::RemoveFile,
+                "/w/a.txt",
+                std::io::ErrorKind::PermissionDenied,
+            );
+        let actions: Vec<_> = ["/w/a.txt", "/w/b.txt"]
+            .into_iter()
+            .map(|p| {
+                PlannedAction::untagged(FileAction::DeleteFile {
+                    path: PathBuf::from(p),
+                })
+            })
+            .collect();
+
+        let err = execute_actions(&fs, &actions, None).unwrap_err();
+
+        assert!(
+            matches!(&err, Error::Io { path, .. } if path == Path::new("/w/a.txt")),
+            "{err:?}"
+        );
+        assert!(
+            fs.exists(Path::new("/w/b.txt")),
+            "execution stops at the first failure"
+        );
+    }
+
+    #[test]
+    fn test_symlink_policies() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/elsewhere/data/keep.txt", "k", hours_ago(500))
+            .add_dir("/elsewhere/data", hours_ago(500))
+            .add_symlink("/w/data", "/elsewhere/data", hours_ago(0))
+            .add_symlink("/w/loop", ".", hours_ago(500))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0));
+        let plan = |symlinks| {
+            let cfg = DirConfig {
+                path: PathBuf::from("/w"),
+                time_to_archive_hours: 24,
+                time_to_deletion_hours: 168,
+                symlinks,
+                ..Default::default()
+            };
+            plan_declutter(&fs, &test_clock(), &cfg).unwrap()
+        };
+
+        assert!(plan(SymlinkPolicy::Skip).is_empty());
+
+        let as_links: Vec<_> = plan(SymlinkPolicy::TreatAsLink)
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            as_links,
+            [FileAction::DeleteFile {
+                path: PathBuf::from("/w/loop")
+            }]
+        );
+
+        let followed = plan(SymlinkPolicy::Follow);
+        let paths: Vec<_> = followed.iter().map(|p| p.action.to_string()).collect();
+        // /w/loop takes the age of /w itself, and sizing it must not recurse forever.
+        assert_eq!(paths, ["delete dir /w/data"]);
+        execute_actions(&fs, &followed, None).unwrap();
+        assert!(!fs.exists(Path::new("/w/data")));
+        assert!(
+            fs.exists(Path::new("/elsewhere/data/keep.txt")),
+            "link targets are never removed"
+        );
+    }
+
+    #[test]
+    fn test_entries_on_other_filesystems_are_skipped() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/w/old.txt", "o", hours_ago(500))
+            .add_file("/w/usb/photo.jpg", "p", hours_ago(500))
+            .add_file("/w/projects/share/doc.txt", "d", hours_ago(500))
+            .add_dir("/w/usb", hours_ago(500))
+            .add_dir("/w/projects", hours_ago(500))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0))
+            .add_mount("/w/usb", 7)
+            .add_mount("/w/projects/share", 0);
+        let mut cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            ..Default::default()
+        };
+
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [FileAction::DeleteFile {
+                path: PathBuf::from("/w/old.txt")
+            }]
+        );
+
+        cfg.allow_cross_device = true;
+        assert_eq!(plan_declutter(&fs, &test_clock(), &cfg).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_dedupe_plans_duplicates_once() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/w/file.pdf", "same", hours_ago(2))
+            .add_file("/w/file (1).pdf", "same", hours_ago(1))
+            .add_file("/w/stale.txt", "old", hours_ago(500))
+            .add_file("/w/stale (1).txt", "old", hours_ago(1))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0));
+        let mut cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            dedupe: true,
+            ..Default::default()
+        };
+
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [
+                FileAction::ArchiveDuplicate {
+                    from: PathBuf::from("/w/file (1).pdf"),
+                    to: PathBuf::from(format!(
+                        "/w/{ARCHIVE_DIR_NAME}/file (1).pdf.20240309T120000Z.bak"
+                    )),
+                    original: PathBuf::from("/w/file.pdf"),
+                },
+                // The kept copy of stale.txt is due for deletion, so its duplicate just ages.
+                FileAction::DeleteFile {
+                    path: PathBuf::from("/w/stale.txt")
+                },
+            ]
+        );
+
+        cfg.dedupe_action = DedupeAction::Delete;
+        cfg.dedupe_keep = DedupeKeep::Newest;
+        let planned = plan_declutter(&fs, &test_clock(), &cfg).unwrap();
+        assert_eq!(
+            planned[0].action,
+            FileAction::DeleteDuplicate {
+                path: PathBuf::from("/w/file.pdf"),
+                original: PathBuf::from("/w/file (1).pdf"),
+            }
+        );
+        assert_eq!(
+            planned[1].action,
+            FileAction::DeleteDuplicate {
+                path: PathBuf::from("/w/stale.txt"),
+                original: PathBuf::from("/w/stale (1).txt"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_move_to_expands_home() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_file("/w/a.pdf", "a", hours_ago(1))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0));
+        let mut cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            rules: vec![toml::from_str("move_to = \"~/Inbox/{ext}\"").unwrap()],
+            ..Default::default()
+        };
+        cfg.expand_path().unwrap();
+
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [FileAction::MoveTo {
+                from: PathBuf::from("/w/a.pdf"),
+                to: home.join("Inbox/pdf/a.pdf"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_dir_sizes_are_only_summed_for_size_conditions() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_sized_file("/w/big/disk.iso", 2000, hours_ago(1))
+            .add_sized_file("/w/small/note.txt", 10, hours_ago(1))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0));
+        let mut cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            ..Default::default()
+        };
+
+        let entries = list_dir_with_meta(
+            &fs,
+            &cfg.path,
+            Some(ARCHIVE_DIR_NAME),
+            test_clock().now().into(),
+            cfg.symlinks,
+        )
+        .unwrap();
+        assert!(
+            entries.iter().all(|e| e.size_bytes == 0),
+            "directories are not crawled while listing"
+        );
+        assert!(!cfg.needs_dir_sizes());
+
+        cfg.rules = vec![toml::from_str("min_size = \"1KiB\"\naction = \"delete\"").unwrap()];
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [FileAction::DeleteDir {
+                path: PathBuf::from("/w/big")
+            }]
+        );
+    }
+
+    #[test]
+    fn test_mounts_are_skipped_before_sizing() {
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_sized_file("/w/usb/photo.jpg", 2000, hours_ago(1))
+            .add_sized_file("/w/local/disk.iso", 2000, hours_ago(1))
+            .add_dir(format!("/w/{ARCHIVE_DIR_NAME}"), hours_ago(0))
+            .add_mount("/w/usb", 7);
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 168,
+            rules: vec![toml::from_str("min_size = \"1KiB\"\naction = \"delete\"").unwrap()],
+            ..Default::default()
+        };
+
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [FileAction::DeleteDir {
+                path: PathBuf::from("/w/local")
+            }]
+        );
+        assert!(fs.read_dirs().contains(&PathBuf::from("/w/local")));
+        assert!(
+            !fs.read_dirs().contains(&PathBuf::from("/w/usb")),
+            "{:?}",
+            fs.read_dirs()
+        );
+    }
+
+    #[test]
+    fn test_pressure_spares_recent_archive_entries_by_default() {
+        let archive = PathBuf::from(format!("/w/{ARCHIVE_DIR_NAME}"));
+        let fs = filesystem::InMemoryFs::new();
+        fs.add_sized_file(archive.join("old.bak"), 3000, hours_ago(100))
+            .add_sized_file(archive.join("newer.bak"), 3000, hours_ago(48))
+            .add_sized_file(archive.join("fresh.bak"), 3000, hours_ago(2))
+            .add_sized_file("/w/download.iso", 500, hours_ago(1))
+            .set_capacity(10_000);
+        let mut cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 1000,
+            min_free_space: Some("20%".parse().unwrap()),
+            ..Default::default()
+        };
+        let deleted = |cfg: &DirConfig| -> Vec<_> {
+            plan_declutter(&fs, &test_clock(), cfg)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.action)
+                .collect()
+        };
+
+        assert_eq!(
+            deleted(&cfg),
+            [FileAction::DeleteFile {
+                path: archive.join("old.bak")
+            }]
+        );
+
+        cfg.min_free_space = Some("90%".parse().unwrap());
+        assert_eq!(
+            deleted(&cfg),
+            [
+                FileAction::DeleteFile {
+                    path: archive.join("old.bak")
+                },
+                FileAction::DeleteFile {
+                    path: archive.join("newer.bak")
+                },
+            ],
+            "entries younger than {DEFAULT_PRESSURE_MIN_AGE_HOURS}h are left alone"
+        );
+    }
+
+    #[test]
+    fn test_pressure_counts_hardlinked_entries_once() {
+        let archive = PathBuf::from(format!("/w/{ARCHIVE_DIR_NAME}"));
+        let fs = filesystem::InMemoryFs::new();
+        let content = "x".repeat(3000);
+        fs.add_file(archive.join("a.1.bak"), content.as_str(), hours_ago(100))
+            .add_file(archive.join("a.2.bak"), content.as_str(), hours_ago(100))
+            .add_file(archive.join("other.bak"), "y".repeat(3000), hours_ago(50))
+            .set_capacity(10_000);
+        store::link_archive(&fs, &archive).unwrap();
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 1000,
+            min_free_space: Some("50%".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let planned: Vec<_> = plan_declutter(&fs, &test_clock(), &cfg)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.action)
+            .collect();
+        assert_eq!(
+            planned,
+            [
+                FileAction::DeleteFile {
+                    path: archive.join("a.1.bak")
+                },
+                FileAction::DeleteFile {
+                    path: archive.join("a.2.bak")
+                },
+            ],
+            "deleting one of two linked entries frees nothing"
+        );
+    }
 }
@@ -0,0 +1,130 @@
+use crate::filesystem::Filesystem;
+use crate::store::STORE_DIR_NAME;
+use crate::{ARCHIVE_DIR_NAME, DirConfig, Error, FileAction, PlannedAction, Result, dir_size};
+use std::collections::HashSet;
+
+/// Refuse a plan for `cfg` that deletes or touches more than the directory's
+/// `max_deletes_per_run`, `max_bytes_deleted_per_run` or `max_fraction` allow.
+///
+/// Sizes and entry counts are only read from `fs` when the matching limit is set.
+pub fn check_run_limits(
+    fs: &dyn Filesystem,
+    cfg: &DirConfig,
+    actions: &[PlannedAction],
+) -> Result<()> {
+    let exceeded = |reason: String| {
+        Err(Error::LimitExceeded {
+            path: cfg.path.clone(),
+            reason,
+        })
+    };
+    let deleted: Vec<_> = actions
+        .iter()
+        .filter_map(|planned| match &planned.action {
+            FileAction::DeleteFile { path }
+            | FileAction::DeleteDir { path }
+            | FileAction::DeleteDuplicate { path, .. } => Some(path),
+            _ => None,
+        })
+        .collect();
+
+    if let Some(max) = cfg.max_deletes_per_run
+        && deleted.len() > max
+    {
+        return exceeded(format!(
+            "plan deletes {} entries, more than max_deletes_per_run ({max})",
+            deleted.len()
+        ));
+    }
+
+    if let Some(max) = cfg.max_bytes_deleted_per_run {
+        let bytes: u64 = deleted
+            .iter()
+            .filter_map(|path| {
+                let meta = fs.metadata(path).ok()?;
+                Some(if meta.is_dir {
+                    dir_size(fs, path, false, &mut HashSet::new())
+                } else {
+                    meta.len
+                })
+            })
+            .sum();
+        if bytes > max.0 {
+            return exceeded(format!(
+                "plan deletes {bytes} bytes, more than max_bytes_deleted_per_run ({max})"
+            ));
+        }
+    }
+
+    if let Some(max) = cfg.max_fraction {
+        let archive_path = cfg.path.join(ARCHIVE_DIR_NAME);
+        let count = |dir| fs.read_dir(dir).map_or(0, |entries| entries.len());
+        let bookkeeping = usize::from(fs.is_dir(&archive_path))
+            + usize::from(fs.is_dir(&archive_path.join(STORE_DIR_NAME)));
+        let total = (count(&cfg.path) + count(&archive_path)).saturating_sub(bookkeeping);
+        let fraction = actions.len() as f64 / total.max(1) as f64;
+        if fraction > max {
+            return exceeded(format!(
+                "plan acts on {} of {total} entries ({:.0}%), more than max_fraction ({max})",
+                actions.len(),
+                fraction * 100.0
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use crate::space::ByteSize;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    fn delete(path: &str) -> PlannedAction {
+        PlannedAction {
+            action: FileAction::DeleteFile {
+                path: PathBuf::from(path),
+            },
+            rule: None,
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let fs = InMemoryFs::new();
+        fs.add_file("/w/a", vec![0; 600], SystemTime::UNIX_EPOCH)
+            .add_file("/w/b", vec![0; 600], SystemTime::UNIX_EPOCH)
+            .add_file("/w/c", "", SystemTime::UNIX_EPOCH)
+            .add_file(
+                format!("/w/{ARCHIVE_DIR_NAME}/d"),
+                "",
+                SystemTime::UNIX_EPOCH,
+            );
+        let actions = [delete("/w/a"), delete("/w/b")];
+        let cfg = |f: fn(&mut DirConfig)| {
+            let mut cfg = DirConfig {
+                path: PathBuf::from("/w"),
+                ..Default::default()
+            };
+            f(&mut cfg);
+            cfg
+        };
+        let reason = |cfg: DirConfig| match check_run_limits(&fs, &cfg, &actions) {
+            Err(Error::LimitExceeded { reason, .. }) => reason,
+            other => panic!("expected a limit error, got {other:?}"),
+        };
+
+        assert!(check_run_limits(&fs, &cfg(|_| {}), &actions).is_ok());
+        assert!(reason(cfg(|c| c.max_deletes_per_run = Some(1))).contains("deletes 2 entries"));
+        assert!(check_run_limits(&fs, &cfg(|c| c.max_deletes_per_run = Some(2)), &actions).is_ok());
+        assert!(
+            reason(cfg(|c| c.max_bytes_deleted_per_run = Some(ByteSize(1000))))
+                .contains("1200 bytes")
+        );
+        assert!(reason(cfg(|c| c.max_fraction = Some(0.4))).contains("2 of 4 entries (50%)"));
+        assert!(check_run_limits(&fs, &cfg(|c| c.max_fraction = Some(0.5)), &actions).is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Directory for per-run state: `$XDG_STATE_HOME/duansheli`, or `~/.local/state/duansheli`.
+pub fn state_dir() -> Result<PathBuf> {
+    let state_home = match (env::var_os("XDG_STATE_HOME"), env::var_os("HOME")) {
+        (Some(state_home), _) => PathBuf::from(state_home),
+        (None, Some(home)) => PathBuf::from(home).join(".local").join("state"),
+        (None, None) => {
+            return Err(Error::Config(
+                "neither XDG_STATE_HOME nor HOME is set; set state_dir for lock files".to_string(),
+            ));
+        }
+    };
+    Ok(state_home.join("duansheli"))
+}
+
+/// Longest part of the directory's own name kept in its lock file name.
+const READABLE_NAME_LEN: usize = 32;
+
+/// Lock file in `state_dir` for a watched directory, named after the
+/// directory's name and a SHA-256 of its canonical path, which keeps names
+/// unique and short whatever the path.
+pub fn lock_path_for(state_dir: &Path, dir: &Path) -> PathBuf {
+    let resolved = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let hash = Sha256::digest(resolved.as_os_str().as_encoded_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    let readable: String = resolved
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(READABLE_NAME_LEN)
+        .collect();
+    state_dir
+        .join("locks")
+        .join(format!("{readable}-{hash}.lock"))
+}
+
+#[derive(Debug)]
+pub struct LockedError {
+    pub path: PathBuf,
+    /// PID recorded by the run holding the lock, if readable.
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for LockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is locked by another duansheli run",
+            self.path.display()
+        )?;
+        if let Some(pid) = self.pid {
+            write!(f, " (pid {pid})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LockedError {}
+
+/// An exclusive advisory lock on a watched directory, released on drop.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock the watched directory `dir`, with the lock file in `state_dir`.
+    ///
+    /// With `wait`, blocks until the current holder finishes; otherwise fails
+    /// immediately with [`Error::Lock`].
+    pub fn acquire(state_dir: &Path, dir: &Path, wait: bool) -> Result<DirLock> {
+        let path = lock_path_for(state_dir, dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::io("create lock directory", parent))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(Error::io("open lock file", &path))?;
+
+        if let Err(e) = flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(Error::io("lock", &path)(e));
+            }
+            let pid = read_pid(&mut file);
+            if let Some(pid) = pid.filter(|&pid| !process_alive(pid)) {
+                // flock dies with its owner, so an inherited descriptor keeps it alive
+                log::warn!(
+                    "Lock {} is held although recorded pid {} has exited; a child process may have inherited it",
+                    path.display(),
+                    pid
+                );
+            }
+            if !wait {
+                return Err(LockedError {
+                    path: dir.to_path_buf(),
+                    pid,
+                }
+                .into());
+            }
+            log::info!("Waiting for lock {}", path.display());
+            flock(&file, libc::LOCK_EX).map_err(Error::io("lock", &path))?;
+        }
+
+        let write_pid = |file: &mut File| -> io::Result<()> {
+            file.set_len(0)?;
+            file.rewind()?;
+            writeln!(file, "{}", std::process::id())
+        };
+        write_pid(&mut file).map_err(Error::io("write lock file", &path))?;
+
+        Ok(DirLock { _file: file })
+    }
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    // SAFETY: the descriptor is owned by `file` and stays open for the call.
+    let rc = unsafe { libc::flock(file.as_raw_fd(), operation) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks for existence and permission.
+    let rc = unsafe { libc::kill(pid, 0) };
+    rc == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_lock_fails_without_wait() {
+        let tmp = TempDir::new().unwrap();
+        let _held = DirLock::acquire(&tmp.path().join("state"), tmp.path(), false).unwrap();
+
+        let err = DirLock::acquire(&tmp.path().join("state"), tmp.path(), false).unwrap_err();
+        let Error::Lock(locked) = err else {
+            panic!("expected a lock error, got {err}");
+        };
+        assert_eq!(locked.pid, Some(std::process::id()));
+    }
+
+    #[test]
+    fn test_lock_is_released_on_drop() {
+        let tmp = TempDir::new().unwrap();
+        drop(DirLock::acquire(&tmp.path().join("state"), tmp.path(), false).unwrap());
+        assert!(DirLock::acquire(&tmp.path().join("state"), tmp.path(), false).is_ok());
+    }
+
+    #[test]
+    fn test_waiting_lock_proceeds_after_release() {
+        let tmp = TempDir::new().unwrap();
+        let held = DirLock::acquire(&tmp.path().join("state"), tmp.path(), false).unwrap();
+
+        let path = tmp.path().to_path_buf();
+        let waiter =
+            std::thread::spawn(move || DirLock::acquire(&path.join("state"), &path, true).is_ok());
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(held);
+
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_lock_path_is_unique_per_dir() {
+        let state = Path::new("/state");
+        assert_ne!(
+            lock_path_for(state, Path::new("/a/b")),
+            lock_path_for(state, Path::new("/a%b"))
+        );
+        assert_ne!(
+            lock_path_for(state, Path::new("/a/%b")),
+            lock_path_for(state, Path::new("/a%/b"))
+        );
+        assert!(lock_path_for(state, Path::new("/a/b")).starts_with(state));
+
+        let deep = PathBuf::from(format!("/{}", ["long directory name"; 40].join("/")));
+        let name = lock_path_for(state, &deep);
+        let name = name.file_name().unwrap().to_string_lossy();
+        assert!(name.len() <= 255, "{name}");
+        assert!(name.starts_with("longdirectoryname-"), "{name}");
+    }
+
+    #[test]
+    fn test_process_alive() {
+        assert!(process_alive(std::process::id()));
+        assert!(!process_alive(u32::MAX));
+    }
+}
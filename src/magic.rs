@@ -0,0 +1,120 @@
+use crate::filesystem::Filesystem;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A content type recognised from a file's leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentKind {
+    /// Short name used by the `kind` rule condition, e.g. `"pdf"`.
+    pub name: &'static str,
+    pub mime: &'static str,
+}
+
+struct Signature {
+    offset: usize,
+    magic: &'static [u8],
+    kind: ContentKind,
+}
+
+const fn sig(
+    offset: usize,
+    magic: &'static [u8],
+    name: &'static str,
+    mime: &'static str,
+) -> Signature {
+    Signature {
+        offset,
+        magic,
+        kind: ContentKind { name, mime },
+    }
+}
+
+/// Checked in order; more specific signatures come before generic containers.
+const SIGNATURES: &[Signature] = &[
+    sig(0, b"%PDF-", "pdf", "application/pdf"),
+    sig(0, b"\x89PNG\r\n\x1a\n", "png", "image/png"),
+    sig(0, b"\xff\xd8\xff", "jpeg", "image/jpeg"),
+    sig(0, b"GIF87a", "gif", "image/gif"),
+    sig(0, b"GIF89a", "gif", "image/gif"),
+    sig(8, b"WEBP", "webp", "image/webp"),
+    sig(4, b"ftyp", "mp4", "video/mp4"),
+    sig(0, b"ID3", "mp3", "audio/mpeg"),
+    sig(0, b"OggS", "ogg", "audio/ogg"),
+    sig(0, b"fLaC", "flac", "audio/flac"),
+    sig(0, b"PK\x03\x04", "zip", "application/zip"),
+    sig(0, b"PK\x05\x06", "zip", "application/zip"),
+    sig(0, b"\x1f\x8b", "gzip", "application/gzip"),
+    sig(0, b"BZh", "bzip2", "application/x-bzip2"),
+    sig(0, b"\xfd7zXZ\x00", "xz", "application/x-xz"),
+    sig(0, b"\x28\xb5\x2f\xfd", "zstd", "application/zstd"),
+    sig(
+        0,
+        b"7z\xbc\xaf\x27\x1c",
+        "7z",
+        "application/x-7z-compressed",
+    ),
+    sig(0, b"Rar!\x1a\x07", "rar", "application/vnd.rar"),
+    sig(257, b"ustar", "tar", "application/x-tar"),
+    sig(0, b"\x7fELF", "elf", "application/x-executable"),
+    sig(
+        0,
+        b"MZ",
+        "exe",
+        "application/vnd.microsoft.portable-executable",
+    ),
+    sig(
+        0,
+        b"SQLite format 3\x00",
+        "sqlite",
+        "application/vnd.sqlite3",
+    ),
+];
+
+/// Enough to cover the furthest signature (`ustar` at offset 257).
+const HEADER_LEN: usize = 512;
+
+/// Identify content from a file's leading bytes.
+pub fn detect(header: &[u8]) -> Option<ContentKind> {
+    SIGNATURES
+        .iter()
+        .find(|s| header.get(s.offset..s.offset + s.magic.len()) == Some(s.magic))
+        .map(|s| s.kind)
+}
+
+/// Read the start of the file at `path` and identify its content.
+pub fn detect_file(fs: &dyn Filesystem, path: &Path) -> io::Result<Option<ContentKind>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    fs.open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(detect(&header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_common_types() {
+        assert_eq!(detect(b"%PDF-1.7\n...").map(|k| k.name), Some("pdf"));
+        assert_eq!(
+            detect(b"\x89PNG\r\n\x1a\n\0\0").map(|k| k.mime),
+            Some("image/png")
+        );
+        assert_eq!(detect(b"\x1f\x8b\x08\0").map(|k| k.name), Some("gzip"));
+        assert_eq!(detect(b"\x7fELF\x02\x01").map(|k| k.name), Some("elf"));
+        assert_eq!(
+            detect(b"RIFF\0\0\0\0WEBPVP8 ").map(|k| k.name),
+            Some("webp")
+        );
+        assert_eq!(detect(b"plain text"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    #[test]
+    fn test_detect_tar_at_offset() {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[257..262].copy_from_slice(b"ustar");
+        assert_eq!(detect(&header).map(|k| k.name), Some("tar"));
+    }
+}
@@ -1,11 +1,17 @@
-use clap::{Parser, Subcommand};
-use duansheli::{DirConfig, declutter_directory};
-use serde::Deserialize;
+use clap::{Parser, Subcommand, ValueEnum};
+use duansheli::check::Severity;
+use duansheli::clock;
+use duansheli::config::Config;
+use duansheli::filesystem::RealFs;
+use duansheli::simulate;
+use duansheli::store;
+use duansheli::systemd::{self, Account, ServiceMode};
+use duansheli::{
+    ARCHIVE_DIR_NAME, Error, Result, RunOptions, daemon, declutter_directory_with, duration, watch,
+};
 use std::env;
-use std::error::Error;
-use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{self, Path, PathBuf};
 use std::process;
 
 /// duansheli - directory declutter & archival tool
@@ -19,7 +25,7 @@ struct Cli {
     /// Increase log verbosity (-v info, -vv debug, -vvv trace)
     #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
-    
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -31,19 +37,90 @@ enum Command {
         /// Simulate actions without making changes
         #[arg(short = 'n', long)]
         dry_run: bool,
+        /// Wait for a concurrent run on the same directory to finish
+        #[arg(long, overrides_with = "no_wait")]
+        wait: bool,
+        /// Fail immediately if another run holds a directory's lock (default)
+        #[arg(long)]
+        no_wait: bool,
+        /// Plan as if it were this UTC instant, e.g. 2024-03-09T12:00:00Z or 2024-03-09
+        #[arg(long, value_parser = clock::parse_timestamp)]
+        now: Option<chrono::DateTime<chrono::Utc>>,
+        /// Act even if a plan exceeds max_deletes_per_run, max_bytes_deleted_per_run
+        /// or max_fraction
+        #[arg(long)]
+        force: bool,
+    },
+    /// Project future runs in memory: when each entry is archived and deleted,
+    /// and how the archive grows. No files are touched.
+    Simulate {
+        /// How many days ahead to simulate
+        #[arg(long, default_value_t = 30)]
+        days: u64,
+        /// Time between simulated runs, e.g. 30m or 1h
+        #[arg(long, default_value = "1h", value_parser = duration::parse_duration)]
+        every: std::time::Duration,
+        /// Start the simulation at this UTC instant instead of now
+        #[arg(long, value_parser = clock::parse_timestamp)]
+        now: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    /// Keep running, processing each directory on its own interval
+    ///
+    /// SIGHUP reloads the config; SIGTERM stops after the current action.
+    Daemon {
+        /// Simulate actions without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Watch directories with inotify and clean up as soon as entries are due
+    ///
+    /// Directories are fully rescanned every `interval` to recover from missed
+    /// events. Signals are handled as in `daemon`.
+    Watch {
+        /// Simulate actions without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
     },
     /// Display the current configuration
     Print,
+    /// Validate the config file, reporting problems with line and column
+    Check,
+    /// Show how much each archive holds and how much hardlinks save
+    Stats,
+    /// Install systemd units that run duansheli for the current config
+    InstallSystemd {
+        /// Install user units instead of system-wide ones
+        #[arg(long)]
+        user: bool,
+        /// Account system-wide units run as [default: the user who ran sudo, or the current user]
+        #[arg(long, value_name = "USER", conflicts_with = "user")]
+        run_as: Option<String>,
+        /// Print the units to stdout instead of installing them
+        #[arg(long)]
+        print: bool,
+        /// How systemd should run duansheli
+        #[arg(long, value_enum, default_value_t = SystemdMode::Timer)]
+        mode: SystemdMode,
+        /// Timer interval, e.g. 30m or 1h (timer mode only)
+        #[arg(long, default_value = "1h", value_parser = duration::parse_duration)]
+        every: std::time::Duration,
+    },
+    /// Remove systemd units written by install-systemd
+    UninstallSystemd {
+        /// Remove user units instead of system-wide ones
+        #[arg(long)]
+        user: bool,
+    },
 }
 
-fn default_config_path() -> PathBuf {
-    let config_home = env::var("XDG_CONFIG_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            let home = env::var("HOME").expect("HOME environment variable not set");
-            PathBuf::from(home).join(".config")
-        });
-    config_home.join("duansheli").join("config.toml")
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SystemdMode {
+    /// Oneshot `run` triggered by a timer
+    Timer,
+    /// Long-running `daemon` service
+    Daemon,
+    /// Long-running `watch` service
+    Watch,
 }
 
 fn init_logging(verbose: u8) {
@@ -60,47 +137,135 @@ fn init_logging(verbose: u8) {
     }
 }
 
+// Exit statuses from sysexits.h, so scripts can tell failures apart.
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_IOERR: i32 = 74;
+const EX_TEMPFAIL: i32 = 75;
+const EX_NOPERM: i32 = 77;
+const EX_CONFIG: i32 = 78;
+
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::DangerousPath { .. } => EX_NOPERM,
+        Error::MissingDirectory { .. } => EX_NOINPUT,
+        Error::Io { .. } => EX_IOERR,
+        Error::Config(_) | Error::Schema(_) => EX_CONFIG,
+        Error::Lock(_) => EX_TEMPFAIL,
+        Error::LimitExceeded { .. } => EX_DATAERR,
+    }
+}
+
+/// What the user can do about `err`, if there is anything obvious.
+fn hint(err: &Error) -> Option<String> {
+    match err {
+        Error::DangerousPath { .. } => {
+            Some("point `path` at a directory that is neither inside nor above a protected one".to_string())
+        }
+        Error::MissingDirectory { .. } => Some("create the directory or remove it from the config".to_string()),
+        Error::Io { path, source, .. } if source.kind() == std::io::ErrorKind::PermissionDenied => {
+            Some(format!("check the permissions of {}", path.display()))
+        }
+        Error::Io { .. } => None,
+        Error::Config(_) | Error::Schema(_) => Some("run `duansheli check` for details".to_string()),
+        Error::Lock(_) => Some("another run is in progress; pass `run --wait` to wait for it".to_string()),
+        Error::LimitExceeded { .. } => {
+            Some("check the thresholds and the plan from `run -n`, then pass `run --force` if it is right".to_string())
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
     init_logging(cli.verbose);
 
-    let config_path = cli.config.unwrap_or_else(default_config_path);
+    let explicit_config = cli.config.clone();
+    let config_path = cli.config.unwrap_or_else(Config::default_path);
+    // `check` has already printed the details its hint would point to.
+    let checking = matches!(cli.command, Some(Command::Check));
 
     let result = match cli.command {
-        Some(Command::Run { dry_run }) => run_declutter(&config_path, dry_run),
-        None => run_declutter(&config_path, false),
+        Some(Command::Run {
+            dry_run,
+            wait,
+            now,
+            force,
+            ..
+        }) => {
+            let opts = RunOptions {
+                dry_run,
+                wait_for_lock: wait,
+                now,
+                force,
+                ..Default::default()
+            };
+            run_declutter(&config_path, &opts)
+        }
+        Some(Command::Daemon { dry_run }) => {
+            let opts = RunOptions {
+                dry_run,
+                ..Default::default()
+            };
+            daemon::run(|| Ok(Config::load(&config_path)?.dirs), &opts)
+        }
+        Some(Command::Watch { dry_run }) => {
+            let opts = RunOptions {
+                dry_run,
+                ..Default::default()
+            };
+            watch::run(|| Ok(Config::load(&config_path)?.dirs), &opts)
+        }
+        Some(Command::Simulate { days, every, now }) => {
+            let horizon = std::time::Duration::from_secs(days.saturating_mul(86400));
+            simulate_config(
+                &config_path,
+                horizon,
+                every,
+                now.unwrap_or_else(chrono::Utc::now),
+            )
+        }
+        None => run_declutter(&config_path, &RunOptions::default()),
         Some(Command::Print) => print_config(&config_path),
+        Some(Command::Check) => check_config(&config_path),
+        Some(Command::Stats) => print_stats(&config_path),
+        Some(Command::InstallSystemd {
+            user,
+            run_as,
+            print,
+            mode,
+            every,
+        }) => {
+            let mode = match mode {
+                SystemdMode::Timer => ServiceMode::Timer { every },
+                SystemdMode::Daemon => ServiceMode::Daemon,
+                SystemdMode::Watch => ServiceMode::Watch,
+            };
+            install_systemd(
+                explicit_config.as_deref(),
+                mode,
+                user,
+                run_as.as_deref(),
+                print,
+            )
+        }
+        Some(Command::UninstallSystemd { user }) => uninstall_systemd(user),
     };
 
     if let Err(e) = result {
-        log::error!("{e}");
-        process::exit(1);
-    }
-}
-
-#[derive(Deserialize)]
-struct DuansheliConfig {
-    dirs: Vec<DirConfig>,
-}
-
-impl fmt::Display for DuansheliConfig {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "  directories :")?;
-        for dir in &self.dirs {
-            writeln!(f, "    - {}", dir.path.display())?;
-            writeln!(f, "      archive after : {} hours", dir.time_to_archive_hours)?;
-            writeln!(f, "      delete after  : {} hours", dir.time_to_deletion_hours)?;
+        match hint(&e).filter(|_| !checking) {
+            Some(hint) => log::error!("{e}\nhint: {hint}"),
+            None => log::error!("{e}"),
         }
-        Ok(())
+        process::exit(exit_code(&e));
     }
 }
 
-fn print_config(config_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn print_config(config_path: &Path) -> Result<()> {
     println!("duansheli configuration");
     println!("  config file : {}", config_path.display());
     match fs::read_to_string(config_path) {
-        Ok(raw) => {
-            let config: DuansheliConfig = toml::from_str(&raw)?;
+        Ok(_) => {
+            let config = Config::load(config_path)?;
             println!("{config}");
         }
         Err(e) => println!("  config      : not found ({e})"),
@@ -108,15 +273,170 @@ fn print_config(config_path: &PathBuf) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn run_declutter(config_path: &PathBuf, dry_run: bool) -> Result<(), Box<dyn Error>> {
-    let config_raw = fs::read_to_string(config_path)?;
-    log::info!("Config Filepath: {}", config_path.display());
-    let config: DuansheliConfig = toml::from_str(&config_raw)?;
+fn check_config(config_path: &Path) -> Result<()> {
+    let raw = fs::read_to_string(config_path).map_err(Error::io("read config", config_path))?;
+    // Problems that stop the config from parsing are diagnostics too, located
+    // in whichever included file they come from.
+    let rendered: Vec<(Severity, String)> = match Config::parse(config_path, &raw) {
+        Ok(config) => config
+            .validate()
+            .iter()
+            .map(|d| (d.diagnostic.severity, d.render()))
+            .collect(),
+        Err(Error::Schema(e)) => vec![(e.diagnostic.severity, e.render())],
+        // Already names the file it is about.
+        Err(e) => vec![(Severity::Error, format!("{}: {e}", Severity::Error))],
+    };
+
+    for (_, diagnostic) in &rendered {
+        println!("{diagnostic}");
+    }
+    let diagnostics: Vec<Severity> = rendered.into_iter().map(|(severity, _)| severity).collect();
+    let errors = diagnostics
+        .iter()
+        .filter(|&&s| s == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        return Err(Error::Config(format!(
+            "{}: {errors} error(s), {warnings} warning(s)",
+            config_path.display()
+        )));
+    }
+    println!("{}: ok ({warnings} warning(s))", config_path.display());
+    Ok(())
+}
+
+fn print_stats(config_path: &Path) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    for dir_config in &config.dirs {
+        let archive_path = dir_config.path.join(ARCHIVE_DIR_NAME);
+        if !archive_path.is_dir() {
+            println!("{}: no archive yet", dir_config.path.display());
+            continue;
+        }
+        let stats = store::archive_stats(&RealFs, &archive_path)?;
+        println!(
+            "{}: {} archived entries, {} bytes, {} bytes on disk, {} bytes saved by hardlinks",
+            dir_config.path.display(),
+            stats.entries,
+            stats.apparent_bytes,
+            stats.stored_bytes,
+            stats.saved_bytes()
+        );
+        if stats.shared_entries > 0 {
+            println!(
+                "  {} entries share content and modification time with another, \
+                 and are kept until the newest is due",
+                stats.shared_entries
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run_declutter(config_path: &Path, opts: &RunOptions) -> Result<()> {
+    let config = Config::load(config_path)?;
 
+    // A directory over its limits is skipped; the others still run.
+    let mut result = Ok(());
     for dir_config in config.dirs {
         log::info!("Processing directory: {}", dir_config.path.display());
-        declutter_directory(dir_config, dry_run)?;
+        match declutter_directory_with(dir_config, opts) {
+            Err(e @ Error::LimitExceeded { .. }) => {
+                if let Err(previous) = std::mem::replace(&mut result, Err(e)) {
+                    log::error!("{previous}");
+                }
+            }
+            other => other?,
+        }
+    }
+
+    result
+}
+
+fn simulate_config(
+    config_path: &Path,
+    horizon: std::time::Duration,
+    every: std::time::Duration,
+    start: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    for dir_config in &config.dirs {
+        print!(
+            "{}",
+            simulate::simulate(&RealFs, dir_config, start, horizon, every)?
+        );
+    }
+
+    Ok(())
+}
+
+fn systemctl_hint(user: bool) -> &'static str {
+    if user {
+        "systemctl --user"
+    } else {
+        "systemctl"
+    }
+}
+
+fn install_systemd(
+    config_path: Option<&Path>,
+    mode: ServiceMode,
+    user: bool,
+    run_as: Option<&str>,
+    print: bool,
+) -> Result<()> {
+    let binary = env::current_exe().map_err(Error::io("locate the duansheli binary", ""))?;
+    let account = if user {
+        None
+    } else {
+        Some(
+            Account::lookup(run_as)
+                .map_err(Error::io("look up the account for system units", ""))?,
+        )
+    };
+    let config_path = systemd::config_path(config_path, account.as_ref());
+    let config_path = path::absolute(&config_path).map_err(Error::io("resolve", &config_path))?;
+    let units = systemd::render_units(&binary, &config_path, mode, account.as_ref());
+
+    if print {
+        for unit in &units {
+            println!("# {}\n{}", unit.name, unit.contents);
+        }
+        return Ok(());
     }
 
+    let unit_dir = systemd::unit_dir(user);
+    for path in
+        systemd::install(&units, &unit_dir).map_err(Error::io("install units into", &unit_dir))?
+    {
+        println!("wrote {}", path.display());
+    }
+    let enable = units.last().expect("at least one unit is rendered").name;
+    let systemctl = systemctl_hint(user);
+    println!("enable with: {systemctl} daemon-reload && {systemctl} enable --now {enable}");
+    Ok(())
+}
+
+fn uninstall_systemd(user: bool) -> Result<()> {
+    let unit_dir = systemd::unit_dir(user);
+    let removed =
+        systemd::uninstall(&unit_dir).map_err(Error::io("remove units from", &unit_dir))?;
+    if removed.is_empty() {
+        println!("no duansheli units installed");
+    }
+    for path in &removed {
+        println!("removed {}", path.display());
+    }
+    println!(
+        "stop any running units with: {} disable --now {} {}",
+        systemctl_hint(user),
+        systemd::TIMER_NAME,
+        systemd::SERVICE_NAME
+    );
     Ok(())
 }
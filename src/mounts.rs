@@ -0,0 +1,114 @@
+use crate::DirEntryWithAge;
+use crate::filesystem::Filesystem;
+use crate::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Mount points of the current mount namespace, from `/proc/self/mountinfo`.
+pub fn mount_points() -> io::Result<Vec<PathBuf>> {
+    Ok(parse_mountinfo(&fs::read_to_string(MOUNTINFO)?))
+}
+
+/// Extract the mount point (fifth field) of each line of a `mountinfo` file.
+fn parse_mountinfo(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| PathBuf::from(unescape(field)))
+        .collect()
+}
+
+/// Undo the octal escapes (`\040` for a space) the kernel uses in `mountinfo`.
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(backslash) = rest.find('\\') {
+        out.push_str(&rest[..backslash]);
+        let code = rest.get(backslash + 1..backslash + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[backslash + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[backslash + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Drop entries of `dir` that live on another device, are mount points, or
+/// have a mount point somewhere below them, so a run never reaches into
+/// another filesystem.
+pub fn skip_other_filesystems(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    entries: Vec<DirEntryWithAge>,
+) -> Result<Vec<DirEntryWithAge>> {
+    if entries.is_empty() {
+        return Ok(entries);
+    }
+    let dev = fs
+        .metadata(dir)
+        .map_err(Error::io("read metadata of", dir))?
+        .dev;
+    let canonical_dir = fs.canonicalize(dir).map_err(Error::io("resolve", dir))?;
+    let mounts = fs
+        .mount_points()
+        .inspect_err(|e| log::warn!("Cannot list mount points, only comparing devices: {}", e))
+        .unwrap_or_default();
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| {
+            let path = Path::new(&entry.path);
+            if fs.metadata(path).is_ok_and(|m| m.dev != dev) {
+                log::info!(
+                    "Skipping {}: on a different device than {}",
+                    entry.path,
+                    dir.display()
+                );
+                return false;
+            }
+            let canonical = canonical_dir.join(path.file_name().unwrap_or_default());
+            if let Some(mount) = mounts.iter().find(|m| m.starts_with(&canonical)) {
+                log::info!(
+                    "Skipping {}: {} is mounted there",
+                    entry.path,
+                    mount.display()
+                );
+                return false;
+            }
+            true
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let contents = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+45 22 0:41 / /mnt/nas\\040share rw,nosuid shared:30 - cifs //nas/share rw
+51 22 259:2 /srv/data /home/me/Downloads/data rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+";
+        assert_eq!(
+            parse_mountinfo(contents),
+            [
+                PathBuf::from("/"),
+                PathBuf::from("/mnt/nas share"),
+                PathBuf::from("/home/me/Downloads/data")
+            ]
+        );
+        assert_eq!(unescape("a\\134b\\x"), "a\\b\\x");
+    }
+}
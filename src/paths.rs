@@ -0,0 +1,178 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Expand a configured path: a leading `~`, `$VAR` / `${VAR}` references, and
+/// `xdg:NAME` for XDG user directories such as `xdg:DOWNLOAD/torrents`.
+pub fn expand_path(raw: &str) -> Result<PathBuf, String> {
+    expand_with(raw, &|name| env::var(name).ok())
+}
+
+fn expand_with(raw: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<PathBuf, String> {
+    if let Some(rest) = raw.strip_prefix("xdg:") {
+        let (name, tail) = rest.split_once('/').unwrap_or((rest, ""));
+        let dir = user_dir(name, lookup)?;
+        return Ok(if tail.is_empty() {
+            dir
+        } else {
+            dir.join(expand_vars(tail, lookup)?)
+        });
+    }
+
+    let raw = match raw.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{rest}", home(lookup)?)
+        }
+        _ => raw.to_string(),
+    };
+    Ok(PathBuf::from(expand_vars(&raw, lookup)?))
+}
+
+fn home(lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    lookup("HOME").ok_or_else(|| "HOME is not set".to_string())
+}
+
+/// Replace `$VAR` and `${VAR}`; a `$` not followed by a name is kept as is.
+fn expand_vars(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, consumed) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{ in {s:?}"))?;
+            (&braced[..end], end + 2)
+        } else {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], end)
+        };
+
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let value =
+            lookup(name).ok_or_else(|| format!("environment variable {name} is not set"))?;
+        out.push_str(&value);
+        rest = &after[consumed..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Resolve an XDG user directory like `DOWNLOAD` from the environment or `user-dirs.dirs`.
+fn user_dir(name: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<PathBuf, String> {
+    let key = format!("XDG_{}_DIR", name.to_ascii_uppercase());
+    if let Some(dir) = lookup(&key) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let config_home = match lookup("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(home(lookup)?).join(".config"),
+    };
+    let file = config_home.join("user-dirs.dirs");
+    let contents = fs::read_to_string(&file)
+        .map_err(|e| format!("xdg:{name}: cannot read {}: {e}", file.display()))?;
+    let value = parse_user_dirs(&contents, &key)
+        .ok_or_else(|| format!("xdg:{name}: {key} is not set in {}", file.display()))?;
+    Ok(PathBuf::from(expand_vars(&value, lookup)?))
+}
+
+/// Find `key` in a `user-dirs.dirs` file, whose lines look like
+/// `XDG_DOWNLOAD_DIR="$HOME/Downloads"`.
+fn parse_user_dirs(contents: &str, key: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let (k, v) = line.trim().split_once('=')?;
+        (k.trim() == key).then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> + use<> {
+        let pairs: Vec<(String, String)> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| {
+            pairs
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        }
+    }
+
+    #[test]
+    fn test_expand_tilde_and_vars() {
+        let lookup = vars(&[("HOME", "/home/me"), ("TEAM", "infra")]);
+
+        assert_eq!(expand_with("~", &lookup), Ok(PathBuf::from("/home/me")));
+        assert_eq!(
+            expand_with("~/Downloads", &lookup),
+            Ok(PathBuf::from("/home/me/Downloads"))
+        );
+        assert_eq!(
+            expand_with("~other/x", &lookup),
+            Ok(PathBuf::from("~other/x"))
+        );
+        assert_eq!(
+            expand_with("$HOME/tmp", &lookup),
+            Ok(PathBuf::from("/home/me/tmp"))
+        );
+        assert_eq!(
+            expand_with("/srv/${TEAM}_scratch", &lookup),
+            Ok(PathBuf::from("/srv/infra_scratch"))
+        );
+        assert_eq!(
+            expand_with("/a/$1/$", &lookup),
+            Ok(PathBuf::from("/a/$1/$"))
+        );
+        assert!(
+            expand_with("$NOPE/x", &lookup)
+                .unwrap_err()
+                .contains("NOPE")
+        );
+        assert!(expand_with("${HOME", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_expand_xdg_user_dir() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(
+            tmp.path().join("user-dirs.dirs"),
+            "# written by xdg-user-dirs-update\nXDG_DOWNLOAD_DIR=\"$HOME/Telechargements\"\n",
+        )
+        .unwrap();
+        let config_home = tmp.path().to_str().unwrap();
+        let lookup = vars(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", config_home)]);
+
+        assert_eq!(
+            expand_with("xdg:DOWNLOAD", &lookup),
+            Ok(PathBuf::from("/home/me/Telechargements"))
+        );
+        assert_eq!(
+            expand_with("xdg:download/torrents", &lookup),
+            Ok(PathBuf::from("/home/me/Telechargements/torrents"))
+        );
+        assert!(
+            expand_with("xdg:MUSIC", &lookup)
+                .unwrap_err()
+                .contains("XDG_MUSIC_DIR")
+        );
+
+        let lookup = vars(&[("XDG_DOWNLOAD_DIR", "/data/dl")]);
+        assert_eq!(
+            expand_with("xdg:DOWNLOAD", &lookup),
+            Ok(PathBuf::from("/data/dl"))
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use crate::DirEntryWithAge;
+use crate::filesystem::Filesystem;
+use crate::sort::{ConflictPolicy, DestTemplate};
+use crate::space::ByteSize;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// A per-directory rule: match conditions plus what to do with matching entries.
+///
+/// All conditions that are set must hold for the rule to match. Without an
+/// explicit `action` or `move_to`, matching entries follow the usual
+/// archive-then-delete lifecycle using the rule's own thresholds.
+///
+/// ```toml
+/// [[dirs.rules]]
+/// id = "big-files"
+/// min_size = "1GiB"
+/// time_to_archive_hours = 1
+/// time_to_deletion_hours = 48
+///
+/// [[dirs.rules]]
+/// glob = "*.torrent"
+/// min_age_hours = 2
+/// action = "delete"
+///
+/// [[dirs.rules]]
+/// extension = ["jpg", "png"]
+/// move_to = "/home/me/Pictures/Unsorted/{year}/{month}"
+/// ```
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Rule {
+    /// Name shown in dry-run output; defaults to the rule's 1-based position.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Shell-style pattern matched against the entry's file name.
+    #[serde(default)]
+    pub glob: Option<GlobPattern>,
+    /// File extensions to match, case-insensitive, without the leading dot.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub extension: Vec<String>,
+    /// Match entries at least this large (directories are summed recursively).
+    #[serde(default)]
+    pub min_size: Option<ByteSize>,
+    /// Match entries at most this large.
+    #[serde(default)]
+    pub max_size: Option<ByteSize>,
+    /// Match entries at least this many hours old.
+    #[serde(default)]
+    pub min_age_hours: Option<u64>,
+    /// Match entries at most this many hours old.
+    #[serde(default)]
+    pub max_age_hours: Option<u64>,
+    #[serde(default)]
+    pub is_dir: Option<bool>,
+    /// Content kinds detected from magic bytes, e.g. `"pdf"` or `["zip", "gzip"]`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub kind: Vec<String>,
+    /// Pattern matched against the detected MIME type, e.g. `"image/*"`.
+    #[serde(default)]
+    pub mime: Option<GlobPattern>,
+    #[serde(default)]
+    pub action: Option<ActionKind>,
+    /// Sort matching entries into this directory; takes precedence over `action`.
+    /// Relative destinations are relative to the watched directory.
+    #[serde(default)]
+    pub move_to: Option<DestTemplate>,
+    /// How `move_to` handles a destination name that is already taken.
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    #[serde(default)]
+    pub time_to_archive_hours: Option<u64>,
+    #[serde(default)]
+    pub time_to_deletion_hours: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    Archive,
+    Delete,
+    Keep,
+}
+
+/// The effective action of a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleAction {
+    /// Archive and delete by age, using the rule's thresholds.
+    Thresholds,
+    Archive,
+    Delete,
+    Keep,
+    MoveTo(DestTemplate, ConflictPolicy),
+}
+
+/// A compiled glob pattern, validated when the config is parsed.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct GlobPattern(glob::Pattern);
+
+impl GlobPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.matches(name)
+    }
+}
+
+impl TryFrom<String> for GlobPattern {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        glob::Pattern::new(&raw)
+            .map(GlobPattern)
+            .map_err(|e| format!("invalid glob {raw:?}: {e}"))
+    }
+}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl Rule {
+    /// Whether the rule matches `entry`, treating `name` as its file name.
+    ///
+    /// The name is passed separately so archived entries can be matched by
+    /// their original name rather than their `.bak` one.
+    pub fn matches(&self, fs: &dyn Filesystem, name: &str, entry: &DirEntryWithAge) -> bool {
+        let age_hours = entry.seconds_since_modification / 3600;
+
+        self.glob.as_ref().is_none_or(|g| g.matches(name))
+            && (self.extension.is_empty() || self.matches_extension(name))
+            && self.min_size.is_none_or(|min| entry.size_bytes >= min.0)
+            && self.max_size.is_none_or(|max| entry.size_bytes <= max.0)
+            && self.min_age_hours.is_none_or(|min| age_hours >= min)
+            && self.max_age_hours.is_none_or(|max| age_hours <= max)
+            && self.is_dir.is_none_or(|is_dir| entry.is_dir == is_dir)
+            // content checks last, since they may read the file
+            && (self.kind.is_empty() || self.matches_kind(fs, entry))
+            && self.mime.as_ref().is_none_or(|m| entry.kind(fs).is_some_and(|k| m.matches(k.mime)))
+    }
+
+    /// Whether the rule sets any match condition; without one it matches everything.
+    pub fn has_conditions(&self) -> bool {
+        self.glob.is_some()
+            || !self.extension.is_empty()
+            || self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.min_age_hours.is_some()
+            || self.max_age_hours.is_some()
+            || self.is_dir.is_some()
+            || !self.kind.is_empty()
+            || self.mime.is_some()
+    }
+
+    fn matches_kind(&self, fs: &dyn Filesystem, entry: &DirEntryWithAge) -> bool {
+        entry.kind(fs).is_some_and(|k| {
+            self.kind
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(k.name))
+        })
+    }
+
+    fn matches_extension(&self, name: &str) -> bool {
+        let Some((_, ext)) = name.rsplit_once('.') else {
+            return false;
+        };
+        self.extension
+            .iter()
+            .any(|wanted| wanted.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+
+    pub fn action(&self) -> RuleAction {
+        if let Some(dest) = &self.move_to {
+            return RuleAction::MoveTo(dest.clone(), self.on_conflict);
+        }
+        match self.action {
+            None => RuleAction::Thresholds,
+            Some(ActionKind::Archive) => RuleAction::Archive,
+            Some(ActionKind::Delete) => RuleAction::Delete,
+            Some(ActionKind::Keep) => RuleAction::Keep,
+        }
+    }
+
+    /// The rule's cutoffs, falling back to `defaults` for unset thresholds.
+    pub fn cutoffs(&self, defaults: Cutoffs) -> Cutoffs {
+        Cutoffs {
+            archive_secs: self
+                .time_to_archive_hours
+                .map_or(defaults.archive_secs, |h| h * 3600),
+            delete_secs: self
+                .time_to_deletion_hours
+                .map_or(defaults.delete_secs, |h| h * 3600),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(glob) = &self.glob {
+            parts.push(format!("glob {glob}"));
+        }
+        if !self.extension.is_empty() {
+            parts.push(format!("extension {}", self.extension.join("|")));
+        }
+        if let Some(min) = self.min_size {
+            parts.push(format!("size >= {min}"));
+        }
+        if let Some(max) = self.max_size {
+            parts.push(format!("size <= {max}"));
+        }
+        if let Some(hours) = self.min_age_hours {
+            parts.push(format!("age >= {hours} hours"));
+        }
+        if let Some(hours) = self.max_age_hours {
+            parts.push(format!("age <= {hours} hours"));
+        }
+        if let Some(is_dir) = self.is_dir {
+            parts.push(if is_dir { "dirs only" } else { "files only" }.to_string());
+        }
+        if !self.kind.is_empty() {
+            parts.push(format!("kind {}", self.kind.join("|")));
+        }
+        if let Some(mime) = &self.mime {
+            parts.push(format!("mime {mime}"));
+        }
+        match self.action() {
+            RuleAction::Thresholds => {
+                if let Some(hours) = self.time_to_archive_hours {
+                    parts.push(format!("archive after {hours} hours"));
+                }
+                if let Some(hours) = self.time_to_deletion_hours {
+                    parts.push(format!("delete after {hours} hours"));
+                }
+            }
+            RuleAction::Archive => parts.push("=> archive".to_string()),
+            RuleAction::Delete => parts.push("=> delete".to_string()),
+            RuleAction::Keep => parts.push("=> keep".to_string()),
+            RuleAction::MoveTo(dest, _) => parts.push(format!("=> move to {dest}")),
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Find the first rule matching `entry`, along with its id.
+pub fn first_match<'a>(
+    fs: &dyn Filesystem,
+    rules: &'a [Rule],
+    name: &str,
+    entry: &DirEntryWithAge,
+) -> Option<(String, &'a Rule)> {
+    rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.matches(fs, name, entry))
+        .map(|(index, rule)| {
+            let id = rule.id.clone().unwrap_or_else(|| format!("#{}", index + 1));
+            (id, rule)
+        })
+}
+
+/// Archive and deletion cutoffs, in seconds, that apply to one entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cutoffs {
+    pub archive_secs: u64,
+    pub delete_secs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use crate::magic;
+    use std::cell::OnceCell;
+
+    fn entry(size_bytes: u64, age_secs: u64, is_dir: bool) -> DirEntryWithAge {
+        DirEntryWithAge {
+            path: "/tmp/root/entry".to_string(),
+            seconds_since_modification: age_secs,
+            is_dir,
+            size_bytes,
+            content_kind: OnceCell::new(),
+        }
+    }
+
+    fn sized(size_bytes: u64) -> DirEntryWithAge {
+        entry(size_bytes, 0, false)
+    }
+
+    const DEFAULTS: Cutoffs = Cutoffs {
+        archive_secs: 24 * 3600,
+        delete_secs: 168 * 3600,
+    };
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let fs = InMemoryFs::new();
+        let rules = vec![
+            Rule {
+                min_size: Some(ByteSize(1000)),
+                time_to_archive_hours: Some(1),
+                ..Default::default()
+            },
+            Rule {
+                id: Some("medium".to_string()),
+                min_size: Some(ByteSize(10)),
+                time_to_archive_hours: Some(2),
+                time_to_deletion_hours: Some(3),
+                ..Default::default()
+            },
+        ];
+
+        let (id, big) = first_match(&fs, &rules, "a", &sized(5000)).unwrap();
+        assert_eq!(id, "#1");
+        assert_eq!(big.cutoffs(DEFAULTS).archive_secs, 3600);
+        assert_eq!(big.cutoffs(DEFAULTS).delete_secs, DEFAULTS.delete_secs);
+
+        let (id, medium) = first_match(&fs, &rules, "a", &sized(100)).unwrap();
+        assert_eq!(id, "medium");
+        assert_eq!(
+            medium.cutoffs(DEFAULTS),
+            Cutoffs {
+                archive_secs: 7200,
+                delete_secs: 10800
+            }
+        );
+
+        assert!(first_match(&fs, &rules, "a", &sized(1)).is_none());
+    }
+
+    #[test]
+    fn test_max_size_bound_is_inclusive() {
+        let fs = InMemoryFs::new();
+        let rule = Rule {
+            max_size: Some(ByteSize(100)),
+            ..Default::default()
+        };
+        assert!(rule.matches(&fs, "a", &sized(100)));
+        assert!(!rule.matches(&fs, "a", &sized(101)));
+    }
+
+    #[test]
+    fn test_rule_parses_human_sizes() {
+        let rule: Rule = toml::from_str("min_size = \"4GiB\"\nmax_size = 1024").unwrap();
+        assert_eq!(rule.min_size, Some(ByteSize(4 << 30)));
+        assert_eq!(rule.max_size, Some(ByteSize(1024)));
+    }
+
+    #[test]
+    fn test_name_conditions() {
+        let fs = InMemoryFs::new();
+        let rule: Rule =
+            toml::from_str("glob = \"report*\"\nextension = [\"PDF\", \".epub\"]").unwrap();
+        assert!(rule.matches(&fs, "report-2024.pdf", &sized(0)));
+        assert!(rule.matches(&fs, "report.EPUB", &sized(0)));
+        assert!(!rule.matches(&fs, "report.txt", &sized(0)));
+        assert!(!rule.matches(&fs, "invoice.pdf", &sized(0)));
+        assert!(!rule.matches(&fs, "report", &sized(0)));
+    }
+
+    #[test]
+    fn test_age_and_kind_conditions() {
+        let fs = InMemoryFs::new();
+        let rule = Rule {
+            min_age_hours: Some(2),
+            max_age_hours: Some(5),
+            is_dir: Some(true),
+            ..Default::default()
+        };
+        assert!(rule.matches(&fs, "d", &entry(0, 3 * 3600, true)));
+        assert!(!rule.matches(&fs, "d", &entry(0, 3 * 3600, false)));
+        assert!(!rule.matches(&fs, "d", &entry(0, 3600, true)));
+        assert!(!rule.matches(&fs, "d", &entry(0, 6 * 3600, true)));
+    }
+
+    #[test]
+    fn test_content_conditions() {
+        let fs = InMemoryFs::new();
+        let pdf = sized(10);
+        pdf.content_kind.set(magic::detect(b"%PDF-1.4")).unwrap();
+        let unknown = sized(10);
+        unknown.content_kind.set(None).unwrap();
+
+        let by_kind: Rule = toml::from_str("kind = [\"zip\", \"PDF\"]").unwrap();
+        assert!(by_kind.matches(&fs, "download (3)", &pdf));
+        assert!(!by_kind.matches(&fs, "download (3)", &unknown));
+
+        let by_mime: Rule = toml::from_str("mime = \"application/*\"").unwrap();
+        assert!(by_mime.matches(&fs, "blob.bin", &pdf));
+        assert!(!by_mime.matches(&fs, "blob.bin", &unknown));
+    }
+
+    #[test]
+    fn test_content_is_not_sniffed_when_cheaper_conditions_fail() {
+        let fs = InMemoryFs::new();
+        let rule: Rule = toml::from_str("extension = \"iso\"\nkind = \"pdf\"").unwrap();
+        let entry = sized(10);
+        assert!(!rule.matches(&fs, "a.txt", &entry));
+        assert!(entry.content_kind.get().is_none());
+    }
+
+    #[test]
+    fn test_rule_actions() {
+        let rule: Rule = toml::from_str("action = \"keep\"").unwrap();
+        assert_eq!(rule.action(), RuleAction::Keep);
+
+        let rule: Rule =
+            toml::from_str("move_to = \"/srv/{ext}\"\non_conflict = \"skip\"").unwrap();
+        assert_eq!(
+            rule.action(),
+            RuleAction::MoveTo(
+                DestTemplate::try_from("/srv/{ext}".to_string()).unwrap(),
+                ConflictPolicy::Skip
+            )
+        );
+
+        assert_eq!(Rule::default().action(), RuleAction::Thresholds);
+        assert!(toml::from_str::<Rule>("action = \"shred\"").is_err());
+        assert!(toml::from_str::<Rule>("glob = \"[\"").is_err());
+    }
+
+    #[test]
+    fn test_content_is_sniffed_through_the_filesystem() {
+        let fs = InMemoryFs::new();
+        fs.add_file(
+            "/tmp/root/entry",
+            "%PDF-1.4\n",
+            std::time::SystemTime::UNIX_EPOCH,
+        );
+        let rule: Rule = toml::from_str("kind = \"pdf\"").unwrap();
+        assert!(rule.matches(&fs, "entry", &sized(9)));
+    }
+}
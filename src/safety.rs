@@ -0,0 +1,216 @@
+use crate::filesystem::Filesystem;
+use crate::{Error, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directories that may hold watched directories but must never be watched
+/// themselves, nor any of their ancestors.
+const PROTECTED_ROOTS: &[&str] = &[
+    "/",
+    "/home",
+    "/root",
+    "/tmp",
+    "/var",
+    "/var/home",
+    "/var/tmp",
+    "/srv",
+    "/mnt",
+    "/media",
+    "/Users",
+    "/Volumes",
+];
+
+/// Directories protected along with everything below them.
+const PROTECTED_TREES: &[&str] = &[
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/opt",
+    "/proc",
+    "/sbin",
+    "/sys",
+    "/usr",
+    "/var/backups",
+    "/var/cache",
+    "/var/db",
+    "/var/empty",
+    "/var/lib",
+    "/var/local",
+    "/var/lock",
+    "/var/log",
+    "/var/mail",
+    "/var/opt",
+    "/var/run",
+    "/var/spool",
+    "/var/www",
+    "/System",
+    "/Library",
+    "/Applications",
+];
+
+/// Directories below `$HOME` protected along with everything below them.
+const PROTECTED_HOME_TREES: &[&str] = &[".ssh", ".gnupg"];
+
+/// A file that protects the directory holding it, and everything below, from duansheli.
+pub const PROTECT_MARKER: &str = ".duansheli-protect";
+
+/// Entries whose presence marks a version-controlled working tree.
+const VCS_MARKERS: &[&str] = &[".git", ".hg", ".svn", ".bzr", ".jj", "_darcs"];
+
+/// Refuse system and home directories, anything that contains one, and
+/// anything inside a protected tree such as `/usr` or `~/.ssh`.
+pub fn validate_path_safety(path: &Path) -> Result<()> {
+    validate_path_safety_with(path, &[])
+}
+
+/// Like [`validate_path_safety`], also protecting the trees in `protected`.
+pub fn validate_path_safety_with(path: &Path, protected: &[PathBuf]) -> Result<()> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    validate_path_safety_for(path, protected, home)
+}
+
+fn validate_path_safety_for(
+    path: &Path,
+    protected: &[PathBuf],
+    home: Option<PathBuf>,
+) -> Result<()> {
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let refuse = |reason: String| {
+        Err(Error::DangerousPath {
+            path: resolved.clone(),
+            reason,
+        })
+    };
+
+    let roots = PROTECTED_ROOTS
+        .iter()
+        .map(PathBuf::from)
+        .chain(home.clone());
+    let trees = PROTECTED_TREES
+        .iter()
+        .map(PathBuf::from)
+        .chain(
+            home.iter()
+                .flat_map(|h| PROTECTED_HOME_TREES.iter().map(move |t| h.join(t))),
+        )
+        .chain(protected.iter().cloned());
+
+    for candidate in [path, resolved.as_path()] {
+        for root in with_resolved(roots.clone()) {
+            if root == candidate {
+                return refuse(format!("{} is protected", root.display()));
+            }
+            if root.starts_with(candidate) {
+                return refuse(format!("contains protected {}", root.display()));
+            }
+        }
+        for tree in with_resolved(trees.clone()) {
+            if candidate.starts_with(&tree) {
+                return refuse(format!("inside protected {}", tree.display()));
+            }
+            if tree.starts_with(candidate) {
+                return refuse(format!("contains protected {}", tree.display()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Each path followed by its canonical form, if that differs.
+fn with_resolved(paths: impl Iterator<Item = PathBuf>) -> impl Iterator<Item = PathBuf> {
+    paths.flat_map(|p| {
+        let resolved = fs::canonicalize(&p).ok().filter(|r| *r != p);
+        [Some(p), resolved].into_iter().flatten()
+    })
+}
+
+/// Refuse a watched directory that is a version-controlled working tree, or
+/// that holds a [`PROTECT_MARKER`] itself or in any ancestor.
+pub fn check_protected_dir(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    let refuse = |reason: String| {
+        Err(Error::DangerousPath {
+            path: dir.to_path_buf(),
+            reason,
+        })
+    };
+    if let Some(marker) = VCS_MARKERS.iter().find(|m| fs.exists(&dir.join(m))) {
+        return refuse(format!(
+            "looks like a version-controlled repository ({marker})"
+        ));
+    }
+    let resolved = fs.canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    if let Some(protected) = resolved.ancestors().find(|a| is_marked(fs, a)) {
+        return refuse(format!("{} contains {PROTECT_MARKER}", protected.display()));
+    }
+    Ok(())
+}
+
+/// Whether `dir` holds a [`PROTECT_MARKER`].
+pub fn is_marked(fs: &dyn Filesystem, dir: &Path) -> bool {
+    fs.exists(&dir.join(PROTECT_MARKER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_protection_is_prefix_and_ancestor_aware() {
+        let reason = |path: &str| match validate_path_safety(Path::new(path)) {
+            Err(Error::DangerousPath { reason, .. }) => reason,
+            other => panic!("{path} should be refused, got {other:?}"),
+        };
+        assert_eq!(reason("/usr/local/share"), "inside protected /usr");
+        assert_eq!(reason("/home"), "/home is protected");
+        let home = env::var("HOME").unwrap();
+        assert!(validate_path_safety(Path::new(&home).parent().unwrap()).is_err());
+        assert_eq!(reason("/"), "/ is protected");
+        assert!(reason(&format!("{home}/.ssh/keys")).starts_with("inside protected"));
+        assert!(validate_path_safety(Path::new("/srv/scratch")).is_ok());
+        assert_eq!(reason("/var/lib/docker"), "inside protected /var/lib");
+        assert_eq!(reason("/var/log"), "inside protected /var/log");
+        assert_eq!(reason("/var"), "/var is protected");
+        assert_eq!(reason("/opt/app/cache"), "inside protected /opt");
+        assert_eq!(reason("/var/tmp"), "/var/tmp is protected");
+        assert!(validate_path_safety(Path::new("/var/tmp/scratch")).is_ok());
+
+        // Fedora Silverblue and friends keep homes in /var/home
+        let home = Some(PathBuf::from("/var/home/alice"));
+        assert!(
+            validate_path_safety_for(Path::new("/var/home/alice/Downloads"), &[], home.clone())
+                .is_ok()
+        );
+        assert!(validate_path_safety_for(Path::new("/var/home/alice"), &[], home.clone()).is_err());
+        assert!(validate_path_safety_for(Path::new("/var/home/alice/.ssh"), &[], home).is_err());
+
+        let extra = [PathBuf::from("/srv/photos")];
+        assert!(validate_path_safety_with(Path::new("/srv/photos/2024"), &extra).is_err());
+        assert!(validate_path_safety_with(Path::new("/srv/scratch"), &extra).is_ok());
+    }
+
+    #[test]
+    fn test_repositories_and_markers_are_refused() {
+        let t = SystemTime::UNIX_EPOCH;
+        let fs = InMemoryFs::new();
+        fs.add_dir("/srv/repo/.git", t)
+            .add_file("/srv/keep/.duansheli-protect", "", t)
+            .add_dir("/srv/keep/inbox", t)
+            .add_dir("/srv/inbox", t);
+
+        assert!(
+            check_protected_dir(&fs, Path::new("/srv/repo"))
+                .unwrap_err()
+                .to_string()
+                .contains(".git")
+        );
+        assert!(check_protected_dir(&fs, Path::new("/srv/keep/inbox")).is_err());
+        assert!(check_protected_dir(&fs, Path::new("/srv/inbox")).is_ok());
+    }
+}
@@ -0,0 +1,428 @@
+use crate::clock::FixedClock;
+use crate::filesystem::{Filesystem, InMemoryFs, Metadata};
+use crate::store::STORE_DIR_NAME;
+use crate::{
+    ARCHIVE_DIR_NAME, DirConfig, Error, FileAction, Result, SymlinkPolicy, execute_actions,
+    fill_dir_sizes, list_dir_with_meta, plan_declutter,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What repeated runs would do to an entry that exists today.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryFate {
+    /// Path relative to the watched directory.
+    pub path: PathBuf,
+    pub archived: Option<DateTime<Utc>>,
+    /// When the entry would be sorted out by a `move_to` rule, and where to.
+    pub sorted: Option<(DateTime<Utc>, PathBuf)>,
+    pub deleted: Option<DateTime<Utc>>,
+}
+
+/// Outcome of [`simulate`].
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub root: PathBuf,
+    pub start: DateTime<Utc>,
+    pub every: Duration,
+    /// Entries of the directory and its archive at `start`, sorted by path.
+    pub entries: Vec<EntryFate>,
+    /// Archive size in bytes after each simulated run.
+    pub archive_size: Vec<(DateTime<Utc>, u64)>,
+}
+
+/// Replay runs of `cfg` every `every` from `start` until `start + horizon`,
+/// against an in-memory copy of the directory's metadata read from `fs`.
+///
+/// Nothing is changed on `fs`. No files are assumed to be open, new files are
+/// not anticipated, and the free-space watermark is not simulated since disk
+/// usage outside the directory cannot be projected.
+pub fn simulate(
+    fs: &dyn Filesystem,
+    cfg: &DirConfig,
+    start: DateTime<Utc>,
+    horizon: Duration,
+    every: Duration,
+) -> Result<Simulation> {
+    crate::validate_path_safety_with(&cfg.path, &cfg.protected_paths)?;
+    if !fs.is_dir(&cfg.path) {
+        return Err(Error::MissingDirectory {
+            path: cfg.path.clone(),
+        });
+    }
+    crate::safety::check_protected_dir(fs, &cfg.path)?;
+    if every.is_zero() {
+        return Err(Error::Config(
+            "simulation step must be longer than zero".to_string(),
+        ));
+    }
+    let to_chrono = |d: Duration| {
+        chrono::Duration::from_std(d)
+            .map_err(|_| Error::Config(format!("simulation period out of range: {d:?}")))
+    };
+    let step = to_chrono(every)?;
+    let end = start
+        .checked_add_signed(to_chrono(horizon)?)
+        .ok_or_else(|| Error::Config(format!("simulation period out of range: {horizon:?}")))?;
+
+    let mut cfg = cfg.clone();
+    if let Some(watermark) = cfg.min_free_space.take() {
+        log::warn!(
+            "Not simulating the free-space watermark of {} on {}",
+            watermark,
+            cfg.path.display()
+        );
+    }
+    // Only sizes are copied, so files of the same size would all hash alike.
+    if std::mem::take(&mut cfg.dedupe) {
+        log::warn!("Not simulating duplicate removal on {}", cfg.path.display());
+    }
+    if std::mem::take(&mut cfg.hardlink_archive) {
+        log::warn!(
+            "Not simulating hardlinks in the archive of {}",
+            cfg.path.display()
+        );
+    }
+
+    let virtual_fs = InMemoryFs::new();
+    copy_mounts(fs, &cfg.path, &virtual_fs)?;
+    let mounts: HashSet<PathBuf> = virtual_fs
+        .mount_points()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    copy_metadata(fs, &cfg.path, &virtual_fs, cfg.symlinks, &mounts)?;
+    let archive_path = cfg.path.join(ARCHIVE_DIR_NAME);
+    virtual_fs.set_now(start.into());
+    virtual_fs
+        .create_dir_all(&archive_path)
+        .map_err(Error::io("create archive", &archive_path))?;
+
+    let mut entries = Vec::new();
+    let mut locations = HashMap::new();
+    for dir in [&cfg.path, &archive_path] {
+        for path in virtual_fs
+            .read_dir(dir)
+            .map_err(Error::io("read directory", dir))?
+        {
+            if path == archive_path {
+                continue;
+            }
+            locations.insert(path.clone(), entries.len());
+            entries.push(EntryFate {
+                path: path.strip_prefix(&cfg.path).unwrap_or(&path).to_path_buf(),
+                archived: None,
+                sorted: None,
+                deleted: None,
+            });
+        }
+    }
+
+    let mut archive_size = Vec::new();
+    let mut now = start;
+    while now <= end {
+        virtual_fs.set_now(now.into());
+        let actions = plan_declutter(&virtual_fs, &FixedClock(now), &cfg)?;
+        execute_actions(&virtual_fs, &actions, None)?;
+
+        for planned in &actions {
+            match &planned.action {
+                FileAction::MoveFile { from, to }
+                | FileAction::MoveDir { from, to }
+                | FileAction::ArchiveDuplicate { from, to, .. } => {
+                    if let Some(i) = locations.remove(from) {
+                        entries[i].archived.get_or_insert(now);
+                        locations.insert(to.clone(), i);
+                    }
+                }
+                FileAction::MoveTo { from, to } => {
+                    if let Some(i) = locations.remove(from) {
+                        entries[i].sorted.get_or_insert((now, to.clone()));
+                        locations.insert(to.clone(), i);
+                    }
+                }
+                FileAction::DeleteFile { path }
+                | FileAction::DeleteDir { path }
+                | FileAction::DeleteDuplicate { path, .. } => {
+                    if let Some(i) = locations.remove(path) {
+                        entries[i].deleted = Some(now);
+                    }
+                }
+            }
+        }
+
+        let mut archived = list_dir_with_meta(
+            &virtual_fs,
+            &archive_path,
+            Some(STORE_DIR_NAME),
+            now.into(),
+            cfg.symlinks,
+        )?;
+        fill_dir_sizes(&virtual_fs, &mut archived, cfg.symlinks);
+        archive_size.push((now, archived.iter().map(|e| e.size_bytes).sum()));
+        now += step;
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(Simulation {
+        root: cfg.path,
+        start,
+        every,
+        entries,
+        archive_size,
+    })
+}
+
+/// Copy the tree below `root` into `into`, keeping sizes and modification times but not contents.
+///
+/// Symlinks become plain files: with the link's own age and size, or under
+/// [`SymlinkPolicy::Follow`] with their target's, which may lie outside `root`.
+/// Mount points, on another device or listed in `mounts`, are copied empty:
+/// runs never act on them, and a mounted share may be huge.
+fn copy_metadata(
+    fs: &dyn Filesystem,
+    root: &Path,
+    into: &InMemoryFs,
+    symlinks: SymlinkPolicy,
+    mounts: &HashSet<PathBuf>,
+) -> Result<()> {
+    let meta = fs
+        .metadata(root)
+        .map_err(Error::io("read metadata of", root))?;
+    let root_dev = meta.dev;
+    into.add_dir(root, meta.modified);
+    for path in fs
+        .read_dir(root)
+        .map_err(Error::io("read directory", root))?
+    {
+        let meta = fs
+            .metadata(&path)
+            .map_err(Error::io("read metadata of", &path))?;
+        if meta.is_dir && (meta.dev != root_dev || mounts.contains(&path)) {
+            into.add_dir(&path, meta.modified)
+                .add_mount(&path, meta.dev);
+            continue;
+        }
+        if meta.is_dir {
+            copy_metadata(fs, &path, into, symlinks, mounts)?;
+            continue;
+        }
+        let meta = match symlinks {
+            SymlinkPolicy::Skip if meta.is_symlink => continue,
+            SymlinkPolicy::Follow if meta.is_symlink => match fs.stat(&path) {
+                Ok(target) if target.is_dir => Metadata {
+                    len: crate::dir_size(fs, &path, true, &mut HashSet::new()),
+                    ..target
+                },
+                Ok(target) => target,
+                Err(_) => meta,
+            },
+            _ => meta,
+        };
+        into.add_sized_file(&path, meta.len, meta.modified);
+    }
+    Ok(())
+}
+
+/// Mark mount points below `root`, such as bind mounts that share its device, in `into`.
+fn copy_mounts(fs: &dyn Filesystem, root: &Path, into: &InMemoryFs) -> Result<()> {
+    let dev = fs
+        .metadata(root)
+        .map_err(Error::io("read metadata of", root))?
+        .dev;
+    into.add_mount(root, dev);
+    let canonical_root = fs.canonicalize(root).map_err(Error::io("resolve", root))?;
+    for mount in fs.mount_points().unwrap_or_default() {
+        match mount.strip_prefix(&canonical_root) {
+            Ok(below) if !below.as_os_str().is_empty() => {
+                let dev = fs.metadata(&mount).map_or(dev, |m| m.dev);
+                into.add_mount(root.join(below), dev);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map_or_else(
+        || "-".to_string(),
+        |t| t.format("%Y-%m-%d %H:%M").to_string(),
+    )
+}
+
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let end = self.archive_size.last().map_or(self.start, |(t, _)| *t);
+        writeln!(
+            f,
+            "{}: {} runs from {} to {}",
+            self.root.display(),
+            self.archive_size.len(),
+            format_time(Some(self.start)),
+            format_time(Some(end))
+        )?;
+
+        let width = self
+            .entries
+            .iter()
+            .map(|e| e.path.as_os_str().len())
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        writeln!(f, "  {:width$}  {:16}  deleted", "entry", "archived")?;
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "  {:width$}  {:16}  {}",
+                entry.path.display(),
+                format_time(entry.archived),
+                format_time(entry.deleted)
+            )?;
+            if let Some((time, to)) = &entry.sorted {
+                writeln!(
+                    f,
+                    "  {:width$}  sorted to {} at {}",
+                    "",
+                    to.display(),
+                    format_time(Some(*time))
+                )?;
+            }
+        }
+
+        writeln!(f, "  archive size:")?;
+        let mut last = None;
+        for (time, bytes) in &self.archive_size {
+            if last != Some(*bytes) {
+                writeln!(f, "    {}  {} bytes", format_time(Some(*time)), bytes)?;
+                last = Some(*bytes);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_simulate_projects_archive_and_delete_times() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        let hours_ago = |h: i64| SystemTime::from(start - chrono::Duration::hours(h));
+        let real = InMemoryFs::new();
+        real.add_dir("/w", hours_ago(0))
+            .add_sized_file("/w/new.txt", 10, hours_ago(0))
+            .add_sized_file("/w/old.txt", 1000, hours_ago(30))
+            .add_sized_file(
+                format!("/w/{ARCHIVE_DIR_NAME}/a.txt.20240301T000000Z.bak"),
+                5,
+                hours_ago(70),
+            );
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 72,
+            ..Default::default()
+        };
+
+        let sim = simulate(
+            &real,
+            &cfg,
+            start,
+            Duration::from_secs(3 * 86400),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        let fate = |name: &str| {
+            sim.entries
+                .iter()
+                .find(|e| e.path == Path::new(name))
+                .unwrap()
+                .clone()
+        };
+        let at = |h: i64| Some(start + chrono::Duration::hours(h));
+        assert_eq!(fate("new.txt").archived, at(24));
+        assert_eq!(fate("new.txt").deleted, at(72));
+        assert_eq!(fate("old.txt").archived, at(0));
+        assert_eq!(fate("old.txt").deleted, at(42));
+        assert_eq!(
+            fate(&format!("{ARCHIVE_DIR_NAME}/a.txt.20240301T000000Z.bak")).deleted,
+            at(2)
+        );
+        assert_eq!(sim.archive_size.len(), 73);
+        assert_eq!(sim.archive_size[0].1, 1005);
+        assert_eq!(sim.archive_size[2].1, 1000);
+        assert_eq!(sim.archive_size[24].1, 1010);
+        assert_eq!(sim.archive_size[72].1, 0);
+        assert!(
+            real.exists(Path::new("/w/old.txt")),
+            "the source is left alone"
+        );
+    }
+
+    #[test]
+    fn test_simulate_keeps_mounts() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        let old = SystemTime::from(start - chrono::Duration::days(30));
+        let real = InMemoryFs::new();
+        real.add_sized_file("/w/old.txt", 1, old)
+            .add_sized_file("/w/nas/film.mkv", 1, old)
+            .add_dir("/w/nas", old)
+            .add_mount("/w/nas", 3);
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 48,
+            ..Default::default()
+        };
+
+        let sim = simulate(
+            &real,
+            &cfg,
+            start,
+            Duration::ZERO,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        let deleted: Vec<_> = sim
+            .entries
+            .iter()
+            .map(|e| (e.path.to_str().unwrap(), e.deleted.is_some()))
+            .collect();
+        assert_eq!(deleted, [("nas", false), ("old.txt", true)]);
+        assert!(
+            !real.read_dirs().contains(&PathBuf::from("/w/nas")),
+            "the mounted share is not walked"
+        );
+    }
+
+    #[test]
+    fn test_simulate_rejects_a_period_past_the_calendar() {
+        let real = InMemoryFs::new();
+        real.add_dir("/w", SystemTime::UNIX_EPOCH);
+        let cfg = DirConfig {
+            path: PathBuf::from("/w"),
+            time_to_archive_hours: 24,
+            time_to_deletion_hours: 48,
+            ..Default::default()
+        };
+
+        let err = simulate(
+            &real,
+            &cfg,
+            Utc::now(),
+            Duration::from_secs(1_000_000_000_000_000),
+            Duration::from_secs(3600),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,309 @@
+use crate::filesystem::Filesystem;
+use chrono::{DateTime, Datelike, Utc};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const PLACEHOLDERS: &[&str] = &["ext", "year", "month"];
+
+/// Destination directory for sorted entries, e.g. `"/home/me/Pictures/{year}/{month}"`.
+///
+/// Supported placeholders are `{ext}` (lowercase extension, `other` if none),
+/// `{year}` and `{month}` (of the entry's modification time, UTC).
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct DestTemplate(String);
+
+impl DestTemplate {
+    pub fn expand(&self, name: &str, modified: DateTime<Utc>) -> PathBuf {
+        let ext = Path::new(name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| "other".to_string());
+
+        let expanded = self
+            .0
+            .replace("{ext}", &ext)
+            .replace("{year}", &format!("{:04}", modified.year()))
+            .replace("{month}", &format!("{:02}", modified.month()));
+        PathBuf::from(expanded)
+    }
+
+    /// Pattern for the name of the child of `dir` that destinations lie in,
+    /// if they lie inside `dir` at all. Placeholders match any name.
+    pub fn child_of(&self, dir: &Path) -> Option<glob::Pattern> {
+        let first = Path::new(&self.0)
+            .strip_prefix(dir)
+            .ok()?
+            .components()
+            .next()?;
+        let mut pattern = String::new();
+        let mut rest = first.as_os_str().to_str()?;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}')?;
+            pattern += &glob::Pattern::escape(&rest[..start]);
+            pattern += "*";
+            rest = &rest[end + 1..];
+        }
+        pattern += &glob::Pattern::escape(rest);
+        glob::Pattern::new(&pattern).ok()
+    }
+
+    /// Expand `~`, environment variables and `xdg:` directories as in
+    /// [`crate::paths::expand_path`], keeping the placeholders for later.
+    pub fn expand_path(&self) -> Result<DestTemplate, String> {
+        let expanded = crate::paths::expand_path(&self.0)?;
+        Ok(DestTemplate(expanded.to_string_lossy().into_owned()))
+    }
+}
+
+impl TryFrom<String> for DestTemplate {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let mut rest = raw.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(format!("unclosed placeholder in {raw:?}"));
+            };
+            let placeholder = &rest[start + 1..start + len];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!(
+                    "unknown placeholder {{{placeholder}}} in {raw:?}, expected one of {{ext}}, {{year}}, {{month}}"
+                ));
+            }
+            rest = &rest[start + len + 1..];
+        }
+        Ok(DestTemplate(raw))
+    }
+}
+
+impl fmt::Display for DestTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What to do when a sorted entry's destination name is already taken.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Append ` (1)`, ` (2)`, ... before the extension.
+    #[default]
+    Rename,
+    /// Leave the entry where it is.
+    Skip,
+}
+
+/// Pick a free destination for `name` inside `dest_dir`.
+///
+/// `claimed` holds targets already handed out in this plan, so two entries
+/// never get sorted onto the same name. Returns `None` if the entry should be
+/// skipped.
+pub fn resolve_target(
+    fs: &dyn Filesystem,
+    dest_dir: &Path,
+    name: &str,
+    policy: ConflictPolicy,
+    claimed: &mut HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    let is_free = |p: &Path, claimed: &HashSet<PathBuf>| !fs.exists(p) && !claimed.contains(p);
+
+    let target = dest_dir.join(name);
+    let resolved = if is_free(&target, claimed) {
+        target
+    } else {
+        match policy {
+            ConflictPolicy::Skip => return None,
+            ConflictPolicy::Rename => {
+                let (stem, ext) = match name.rsplit_once('.') {
+                    Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+                    _ => (name, String::new()),
+                };
+                (1..)
+                    .map(|n| dest_dir.join(format!("{stem} ({n}){ext}")))
+                    .find(|candidate| is_free(candidate, claimed))
+                    .expect("an unused name should exist")
+            }
+        }
+    };
+
+    claimed.insert(resolved.clone());
+    Some(resolved)
+}
+
+/// Move `from` to `to`, copying across filesystems when a rename is not possible.
+///
+/// Refuses to replace an existing destination.
+pub fn move_entry(fs: &dyn Filesystem, from: &Path, to: &Path) -> io::Result<()> {
+    if fs.exists(to) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("destination already exists: {}", to.display()),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs.create_dir_all(parent)?;
+    }
+
+    match fs.rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!("Copying {} across filesystems", from.display());
+            copy_recursive(fs, from, to)?;
+            if fs.is_dir(from) {
+                fs.remove_dir_all(from)
+            } else {
+                fs.remove_file(from)
+            }
+        }
+        other => other,
+    }
+}
+
+fn copy_recursive(fs: &dyn Filesystem, from: &Path, to: &Path) -> io::Result<()> {
+    if !fs.metadata(from)?.is_dir {
+        return fs.copy(from, to).map(|_| ());
+    }
+    fs.create_dir_all(to)?;
+    for entry in fs.read_dir(from)? {
+        let name = entry.file_name().expect("directory entries have names");
+        copy_recursive(fs, &entry, &to.join(name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsOp, InMemoryFs, RealFs};
+    use chrono::TimeZone;
+    use std::fs;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_expand_template() {
+        let template = DestTemplate::try_from("/srv/{ext}/{year}-{month}".to_string()).unwrap();
+        let modified = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            template.expand("Scan.PDF", modified),
+            PathBuf::from("/srv/pdf/2024-03")
+        );
+        assert_eq!(
+            template.expand("README", modified),
+            PathBuf::from("/srv/other/2024-03")
+        );
+    }
+
+    #[test]
+    fn test_child_of_names_the_destination_inside_a_directory() {
+        let dest = |raw: &str| DestTemplate::try_from(raw.to_string()).unwrap();
+        let child = dest("/w/Sorted [{ext}]/{year}")
+            .child_of(Path::new("/w"))
+            .unwrap();
+        assert!(child.matches("Sorted [pdf]"));
+        assert!(!child.matches("Sorted pdf"));
+        assert!(
+            dest("/w/Pictures")
+                .child_of(Path::new("/w"))
+                .unwrap()
+                .matches("Pictures")
+        );
+        assert!(dest("/srv/{ext}").child_of(Path::new("/w")).is_none());
+        assert!(dest("/w").child_of(Path::new("/w")).is_none());
+    }
+
+    #[test]
+    fn test_template_rejects_unknown_placeholders() {
+        assert!(DestTemplate::try_from("/srv/{day}".to_string()).is_err());
+        assert!(DestTemplate::try_from("/srv/{ext".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_target_renames_on_conflict() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("a.pdf"), "x").unwrap();
+        let mut claimed = HashSet::new();
+
+        let first = resolve_target(
+            &RealFs,
+            tmp.path(),
+            "a.pdf",
+            ConflictPolicy::Rename,
+            &mut claimed,
+        );
+        let second = resolve_target(
+            &RealFs,
+            tmp.path(),
+            "a.pdf",
+            ConflictPolicy::Rename,
+            &mut claimed,
+        );
+
+        assert_eq!(first, Some(tmp.path().join("a (1).pdf")));
+        assert_eq!(second, Some(tmp.path().join("a (2).pdf")));
+    }
+
+    #[test]
+    fn test_resolve_target_skips_on_conflict() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("a.pdf"), "x").unwrap();
+        let mut claimed = HashSet::new();
+
+        assert_eq!(
+            resolve_target(
+                &RealFs,
+                tmp.path(),
+                "a.pdf",
+                ConflictPolicy::Skip,
+                &mut claimed
+            ),
+            None
+        );
+        assert_eq!(
+            resolve_target(
+                &RealFs,
+                tmp.path(),
+                "b.pdf",
+                ConflictPolicy::Skip,
+                &mut claimed
+            ),
+            Some(tmp.path().join("b.pdf"))
+        );
+    }
+
+    #[test]
+    fn test_move_entry_refuses_to_clobber() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("from.txt");
+        let to = tmp.path().join("nested/to.txt");
+        fs::write(&from, "new").unwrap();
+
+        move_entry(&RealFs, &from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+
+        fs::write(&from, "newer").unwrap();
+        assert!(move_entry(&RealFs, &from, &to).is_err());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_move_entry_copies_across_devices() {
+        let t = SystemTime::UNIX_EPOCH;
+        let mem = InMemoryFs::new();
+        mem.add_file("/src/photos/a.jpg", "jpeg", t)
+            .add_dir("/mnt/usb", t)
+            .fail(FsOp::Rename, "/src/photos", io::ErrorKind::CrossesDevices);
+
+        move_entry(&mem, Path::new("/src/photos"), Path::new("/mnt/usb/photos")).unwrap();
+
+        assert_eq!(
+            mem.contents("/mnt/usb/photos/a.jpg"),
+            Some(b"jpeg".to_vec())
+        );
+        assert!(!mem.exists(Path::new("/src/photos")));
+    }
+}
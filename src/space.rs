@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Free-space watermark for the filesystem holding a watched directory,
+/// e.g. `"10%"` or `"5GiB"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum FreeSpace {
+    Percent(f64),
+    Bytes(u64),
+}
+
+impl FreeSpace {
+    /// Number of bytes that must be available on a filesystem of `total_bytes`.
+    pub fn required_bytes(&self, total_bytes: u64) -> u64 {
+        match *self {
+            FreeSpace::Percent(pct) => (total_bytes as f64 * pct / 100.0).ceil() as u64,
+            FreeSpace::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+impl TryFrom<String> for FreeSpace {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl std::str::FromStr for FreeSpace {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let s = raw.trim();
+        if let Some(pct) = s.strip_suffix('%') {
+            let pct: f64 = pct
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid percentage: {raw:?}"))?;
+            if !(0.0..=100.0).contains(&pct) {
+                return Err(format!("percentage out of range 0-100: {raw:?}"));
+            }
+            return Ok(FreeSpace::Percent(pct));
+        }
+        parse_size(s).map(FreeSpace::Bytes)
+    }
+}
+
+impl fmt::Display for FreeSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreeSpace::Percent(pct) => write!(f, "{pct}%"),
+            FreeSpace::Bytes(bytes) => write!(f, "{bytes} bytes"),
+        }
+    }
+}
+
+/// Parse a byte size such as `"512"`, `"100MB"` or `"5GiB"`.
+///
+/// Suffixes `KB`/`MB`/`GB`/`TB` are decimal; `K`/`M`/`G`/`T` and the `iB`
+/// forms are binary.
+pub fn parse_size(raw: &str) -> Result<u64, String> {
+    let s = raw.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {raw:?}"))?;
+
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        other => return Err(format!("unknown size unit {other:?} in {raw:?}")),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A byte size in config, given either as an integer or a string like `"4GiB"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "SizeRepr")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeRepr {
+    Int(u64),
+    Str(String),
+}
+
+impl TryFrom<SizeRepr> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw: SizeRepr) -> Result<Self, Self::Error> {
+        match raw {
+            SizeRepr::Int(bytes) => Ok(ByteSize(bytes)),
+            SizeRepr::Str(s) => parse_size(&s).map(ByteSize),
+        }
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.0)
+    }
+}
+
+/// Capacity of the filesystem containing a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl DiskUsage {
+    /// Bytes that still have to be freed to satisfy `watermark`.
+    pub fn deficit(&self, watermark: FreeSpace) -> u64 {
+        watermark
+            .required_bytes(self.total_bytes)
+            .saturating_sub(self.available_bytes)
+    }
+}
+
+/// Query the filesystem holding `path` via `statvfs`.
+#[allow(clippy::unnecessary_cast)] // statvfs field widths vary by platform
+pub fn disk_usage(path: &Path) -> io::Result<DiskUsage> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is only
+    // read after statvfs reports success.
+    let rc = unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    let fragment = stat.f_frsize as u64;
+    Ok(DiskUsage {
+        total_bytes: stat.f_blocks as u64 * fragment,
+        available_bytes: stat.f_bavail as u64 * fragment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_free_space() {
+        assert_eq!("10%".parse(), Ok(FreeSpace::Percent(10.0)));
+        assert_eq!("5GiB".parse(), Ok(FreeSpace::Bytes(5 << 30)));
+        assert_eq!("500MB".parse(), Ok(FreeSpace::Bytes(500_000_000)));
+        assert_eq!("1024".parse(), Ok(FreeSpace::Bytes(1024)));
+        assert!("150%".parse::<FreeSpace>().is_err());
+        assert!("5 parsecs".parse::<FreeSpace>().is_err());
+    }
+
+    #[test]
+    fn test_deficit() {
+        let usage = DiskUsage {
+            total_bytes: 1000,
+            available_bytes: 50,
+        };
+        assert_eq!(usage.deficit(FreeSpace::Percent(10.0)), 50);
+        assert_eq!(usage.deficit(FreeSpace::Bytes(30)), 0);
+    }
+
+    #[test]
+    fn test_disk_usage_of_temp_dir() {
+        let usage = disk_usage(&std::env::temp_dir()).unwrap();
+        assert!(usage.total_bytes >= usage.available_bytes);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::dedupe::hash_file;
+use crate::filesystem::Filesystem;
+use crate::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory inside the archive holding one blob per distinct file content,
+/// named by its SHA-256 and hardlinked from every archive entry with that content.
+pub const STORE_DIR_NAME: &str = ".store";
+
+/// Suffix of the temporary link made beside an entry before replacing it.
+const TEMP_LINK_SUFFIX: &str = ".link";
+
+/// Where an entry is linked to its blob before being renamed over: hidden,
+/// and never ending in `.bak` like real archive entries do.
+fn temp_link_for(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}{TEMP_LINK_SUFFIX}"))
+}
+
+/// Whether `path` is a temporary link left behind by an interrupted [`link_archive`].
+pub fn is_temp_link(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') && name.ends_with(TEMP_LINK_SUFFIX)
+}
+
+/// Hardlink each file directly inside `archive_path` to the store blob with
+/// the same content, creating the blob if it is new.
+///
+/// Files that already have more than one link are skipped, so each file is
+/// only hashed once. Linked entries share one modification time; the blob
+/// takes the newest of them, so no entry is deleted before its own cutoff,
+/// but older entries are kept until the newest identical one is due.
+/// Temporary links left by an interrupted run are removed first.
+pub fn link_archive(fs: &dyn Filesystem, archive_path: &Path) -> Result<()> {
+    let store = archive_path.join(STORE_DIR_NAME);
+    for path in fs
+        .read_dir(archive_path)
+        .map_err(Error::io("read directory", archive_path))?
+    {
+        if is_temp_link(&path) {
+            log::info!(
+                "Removing {}: left over from an interrupted run",
+                path.display()
+            );
+            fs.remove_file(&path).map_err(Error::io("remove", &path))?;
+        } else if path != store
+            && let Err(e) = link_into_store(fs, &store, &path)
+        {
+            log::warn!(
+                "Cannot link {} into the archive store: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+fn link_into_store(fs: &dyn Filesystem, store: &Path, path: &Path) -> io::Result<()> {
+    let meta = fs.metadata(path)?;
+    if meta.is_dir || meta.is_symlink || meta.len == 0 || meta.nlink > 1 {
+        return Ok(());
+    }
+    let hash = hash_file(fs, path)?
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    let blob = store.join(&hash[..2]).join(&hash[2..]);
+
+    match fs.metadata(&blob) {
+        Ok(stored) => {
+            if meta.modified > stored.modified {
+                fs.set_modified(&blob, meta.modified)?;
+            }
+            // Link beside the entry and rename over it, so its name never goes missing.
+            let temp = temp_link_for(path);
+            fs.hard_link(&blob, &temp)?;
+            fs.rename(&temp, path)?;
+            log::info!("Linked {} to identical archived content", path.display());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs.create_dir_all(blob.parent().expect("blob is inside the store"))?;
+            fs.hard_link(path, &blob)?;
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Remove store blobs that no archive entry links to any more.
+pub fn collect_garbage(fs: &dyn Filesystem, archive_path: &Path) -> Result<()> {
+    let store = archive_path.join(STORE_DIR_NAME);
+    if !fs.is_dir(&store) {
+        return Ok(());
+    }
+    for bucket in fs
+        .read_dir(&store)
+        .map_err(Error::io("read directory", &store))?
+    {
+        let blobs = fs
+            .read_dir(&bucket)
+            .map_err(Error::io("read directory", &bucket))?;
+        let mut remaining = blobs.len();
+        for blob in blobs {
+            if fs.metadata(&blob).is_ok_and(|m| m.nlink == 1) {
+                log::info!("Removing {}: no archive entry links to it", blob.display());
+                fs.remove_file(&blob).map_err(Error::io("remove", &blob))?;
+                remaining -= 1;
+            }
+        }
+        if remaining == 0 {
+            fs.remove_dir_all(&bucket)
+                .map_err(Error::io("remove", &bucket))?;
+        }
+    }
+    Ok(())
+}
+
+/// Size of an archive, counting hardlinked content once and ignoring the store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveStats {
+    pub entries: usize,
+    /// Bytes the archive would take if every entry were a separate copy.
+    pub apparent_bytes: u64,
+    /// Bytes it actually takes, with every hardlinked file counted once.
+    pub stored_bytes: u64,
+    /// Entries hardlinked to another entry, so sharing its modification
+    /// time and kept until the newest of them is due for deletion.
+    pub shared_entries: usize,
+}
+
+impl ArchiveStats {
+    /// Bytes saved by hardlinking identical archive entries.
+    pub fn saved_bytes(&self) -> u64 {
+        self.apparent_bytes - self.stored_bytes
+    }
+}
+
+pub fn archive_stats(fs: &dyn Filesystem, archive_path: &Path) -> Result<ArchiveStats> {
+    let store = archive_path.join(STORE_DIR_NAME);
+    let mut stats = ArchiveStats::default();
+    let mut seen = HashSet::new();
+    let mut pending = Vec::new();
+    let mut links: HashMap<(u64, u64), usize> = HashMap::new();
+    for path in fs
+        .read_dir(archive_path)
+        .map_err(Error::io("read directory", archive_path))?
+    {
+        if path != store && !is_temp_link(&path) {
+            stats.entries += 1;
+            if let Ok(meta) = fs.metadata(&path)
+                && !meta.is_dir
+                && meta.nlink > 1
+            {
+                *links.entry((meta.dev, meta.ino)).or_default() += 1;
+            }
+            pending.push(path);
+        }
+    }
+    stats.shared_entries = links.into_values().filter(|&n| n > 1).sum();
+    while let Some(path) = pending.pop() {
+        let meta = fs
+            .metadata(&path)
+            .map_err(Error::io("read metadata of", &path))?;
+        if meta.is_dir {
+            pending.extend(
+                fs.read_dir(&path)
+                    .map_err(Error::io("read directory", &path))?,
+            );
+        } else if !meta.is_symlink {
+            stats.apparent_bytes += meta.len;
+            if seen.insert((meta.dev, meta.ino)) {
+                stats.stored_bytes += meta.len;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::InMemoryFs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_identical_archive_entries_share_a_blob() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let archive = PathBuf::from("/w/archive");
+        let fs = InMemoryFs::new();
+        fs.add_file(archive.join("big.iso.1.bak"), "data", t)
+            .add_file(
+                archive.join("big.iso.2.bak"),
+                "data",
+                t + Duration::from_secs(60),
+            )
+            .add_file(archive.join("other.bak"), "else", t);
+
+        link_archive(&fs, &archive).unwrap();
+        let first = fs.metadata(&archive.join("big.iso.1.bak")).unwrap();
+        assert_eq!(first.nlink, 3);
+        assert_eq!(
+            first.modified,
+            t + Duration::from_secs(60),
+            "linked entries take the newest time"
+        );
+        assert_eq!(fs.metadata(&archive.join("other.bak")).unwrap().nlink, 2);
+        let stats = archive_stats(&fs, &archive).unwrap();
+        assert_eq!(
+            (
+                stats.entries,
+                stats.apparent_bytes,
+                stats.stored_bytes,
+                stats.saved_bytes()
+            ),
+            (3, 12, 8, 4)
+        );
+        assert_eq!(stats.shared_entries, 2);
+
+        fs.remove_file(&archive.join("big.iso.1.bak")).unwrap();
+        collect_garbage(&fs, &archive).unwrap();
+        assert_eq!(
+            fs.metadata(&archive.join("big.iso.2.bak")).unwrap().nlink,
+            2,
+            "one link is left, so the blob stays"
+        );
+
+        fs.remove_file(&archive.join("big.iso.2.bak")).unwrap();
+        fs.remove_file(&archive.join("other.bak")).unwrap();
+        collect_garbage(&fs, &archive).unwrap();
+        assert_eq!(
+            fs.read_dir(&archive.join(STORE_DIR_NAME)).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn test_leftover_temp_links_are_removed() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let archive = PathBuf::from("/w/archive");
+        let fs = InMemoryFs::new();
+        fs.add_file(archive.join("a.bak"), "data", t).add_file(
+            archive.join(".a.bak.link"),
+            "data",
+            t,
+        );
+        assert!(is_temp_link(&archive.join(".a.bak.link")));
+        assert!(!is_temp_link(
+            &archive.join("..profile.link.20240309T120000Z.bak")
+        ));
+        assert_eq!(archive_stats(&fs, &archive).unwrap().entries, 1);
+
+        link_archive(&fs, &archive).unwrap();
+        assert!(!fs.exists(&archive.join(".a.bak.link")));
+        assert!(fs.exists(&archive.join("a.bak")));
+    }
+}
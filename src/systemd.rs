@@ -0,0 +1,370 @@
+use crate::config::Config;
+use crate::duration::Interval;
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const SERVICE_NAME: &str = "duansheli.service";
+pub const TIMER_NAME: &str = "duansheli.timer";
+
+/// How systemd should run duansheli.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceMode {
+    /// A oneshot `run` triggered by a timer every `every`.
+    Timer { every: Duration },
+    /// A long-running `daemon` service.
+    Daemon,
+    /// A long-running `watch` service.
+    Watch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitFile {
+    pub name: &'static str,
+    pub contents: String,
+}
+
+/// Where unit files go: the user's systemd dir with `user`, otherwise `/etc/systemd/system`.
+pub fn unit_dir(user: bool) -> PathBuf {
+    if !user {
+        return PathBuf::from("/etc/systemd/system");
+    }
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            let home = env::var("HOME").expect("HOME environment variable not set");
+            PathBuf::from(home).join(".config")
+        });
+    config_home.join("systemd").join("user")
+}
+
+/// The account a system unit runs as, so it sees that user's home and files
+/// instead of acting on them as root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub user: String,
+    pub group: String,
+    pub home: PathBuf,
+}
+
+impl Account {
+    /// Look up `name`, defaulting to the user who invoked `sudo`, then the current user.
+    pub fn lookup(name: Option<&str>) -> io::Result<Account> {
+        let name = name
+            .map(str::to_string)
+            .or_else(|| env::var("SUDO_USER").ok());
+        // SAFETY: the returned entries point into libc's static buffers and are
+        // copied out before the next lookup; duansheli does not look users up concurrently.
+        unsafe {
+            let passwd = match &name {
+                Some(name) => {
+                    let c_name = CString::new(name.as_str())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    libc::getpwnam(c_name.as_ptr())
+                }
+                None => libc::getpwuid(libc::getuid()),
+            };
+            if passwd.is_null() {
+                let who = name.unwrap_or_else(|| "the current user".to_string());
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such user: {who}"),
+                ));
+            }
+            let user = CStr::from_ptr((*passwd).pw_name)
+                .to_string_lossy()
+                .into_owned();
+            let home = PathBuf::from(
+                CStr::from_ptr((*passwd).pw_dir)
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+            let gid = (*passwd).pw_gid;
+            let group = libc::getgrgid(gid);
+            let group = if group.is_null() {
+                gid.to_string()
+            } else {
+                CStr::from_ptr((*group).gr_name)
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            Ok(Account { user, group, home })
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{escaped}\"")
+}
+
+/// Render the unit files for `mode`, invoking `binary` with `config`.
+///
+/// Without `account` they are user units; with it they are system units
+/// that run as that account, with its home directory as `HOME`.
+pub fn render_units(
+    binary: &Path,
+    config: &Path,
+    mode: ServiceMode,
+    account: Option<&Account>,
+) -> Vec<UnitFile> {
+    let exec = format!(
+        "{} --config {}",
+        quote(&binary.to_string_lossy()),
+        quote(&config.to_string_lossy())
+    );
+    let install_target = if account.is_none() {
+        "default.target"
+    } else {
+        "multi-user.target"
+    };
+    let run_as = account.map_or_else(String::new, |a| {
+        format!(
+            "User={}\nGroup={}\nEnvironment={}\n",
+            a.user,
+            a.group,
+            quote(&format!("HOME={}", a.home.display()))
+        )
+    });
+
+    match mode {
+        ServiceMode::Timer { every } => vec![
+            UnitFile {
+                name: SERVICE_NAME,
+                contents: format!(
+                    "[Unit]\n\
+                     Description=duansheli directory declutter\n\
+                     \n\
+                     [Service]\n\
+                     Type=oneshot\n\
+                     {run_as}\
+                     ExecStart={exec} run\n"
+                ),
+            },
+            UnitFile {
+                name: TIMER_NAME,
+                contents: format!(
+                    "[Unit]\n\
+                     Description=Run duansheli every {every}\n\
+                     \n\
+                     [Timer]\n\
+                     OnBootSec=5min\n\
+                     OnUnitActiveSec={secs}s\n\
+                     \n\
+                     [Install]\n\
+                     WantedBy=timers.target\n",
+                    every = Interval(every),
+                    secs = every.as_secs(),
+                ),
+            },
+        ],
+        ServiceMode::Daemon | ServiceMode::Watch => {
+            let subcommand = if mode == ServiceMode::Daemon {
+                "daemon"
+            } else {
+                "watch"
+            };
+            vec![UnitFile {
+                name: SERVICE_NAME,
+                contents: format!(
+                    "[Unit]\n\
+                     Description=duansheli directory declutter ({subcommand})\n\
+                     \n\
+                     [Service]\n\
+                     Type=simple\n\
+                     {run_as}\
+                     ExecStart={exec} {subcommand}\n\
+                     ExecReload=/bin/kill -HUP $MAINPID\n\
+                     Restart=on-failure\n\
+                     \n\
+                     [Install]\n\
+                     WantedBy={install_target}\n"
+                ),
+            }]
+        }
+    }
+}
+
+/// Write `units` into `dir`, removing duansheli units from another mode.
+pub fn install(units: &[UnitFile], dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    for stale in [SERVICE_NAME, TIMER_NAME] {
+        if !units.iter().any(|u| u.name == stale) {
+            remove_if_exists(&dir.join(stale))?;
+        }
+    }
+
+    units
+        .iter()
+        .map(|unit| {
+            let path = dir.join(unit.name);
+            fs::write(&path, &unit.contents)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Remove any duansheli unit files from `dir`, returning the removed paths.
+pub fn uninstall(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for name in [SERVICE_NAME, TIMER_NAME] {
+        let path = dir.join(name);
+        if remove_if_exists(&path)? {
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The config the units should read: `explicit` if given, otherwise the
+/// default config of the account system units run as, so that running under
+/// `sudo` does not point them at root's.
+pub fn config_path(explicit: Option<&Path>, account: Option<&Account>) -> PathBuf {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    match (explicit, account) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(account)) if home.as_ref() != Some(&account.home) => {
+            Config::default_path_in(&account.home)
+        }
+        (None, _) => Config::default_path(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_render_timer_units() {
+        let units = render_units(
+            Path::new("/usr/local/bin/duansheli"),
+            Path::new("/home/me/.config/duansheli/config.toml"),
+            ServiceMode::Timer {
+                every: Duration::from_secs(3600),
+            },
+            None,
+        );
+
+        assert_eq!(units.len(), 2);
+        assert!(units[0].contents.contains(
+            "ExecStart=\"/usr/local/bin/duansheli\" --config \"/home/me/.config/duansheli/config.toml\" run"
+        ));
+        assert!(units[0].contents.contains("Type=oneshot"));
+        assert_eq!(units[1].name, TIMER_NAME);
+        assert!(units[1].contents.contains("OnUnitActiveSec=3600s"));
+        assert!(!units[0].contents.contains("User="));
+    }
+
+    #[test]
+    fn test_render_daemon_unit() {
+        let account = Account {
+            user: "me".to_string(),
+            group: "staff".to_string(),
+            home: PathBuf::from("/home/me"),
+        };
+        let units = render_units(
+            Path::new("/bin/duansheli"),
+            Path::new("/etc/d.toml"),
+            ServiceMode::Daemon,
+            Some(&account),
+        );
+
+        assert_eq!(
+            units[0].contents,
+            "[Unit]\n\
+             Description=duansheli directory declutter (daemon)\n\
+             \n\
+             [Service]\n\
+             Type=simple\n\
+             User=me\n\
+             Group=staff\n\
+             Environment=\"HOME=/home/me\"\n\
+             ExecStart=\"/bin/duansheli\" --config \"/etc/d.toml\" daemon\n\
+             ExecReload=/bin/kill -HUP $MAINPID\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n"
+        );
+    }
+
+    #[test]
+    fn test_system_units_read_the_accounts_own_config() {
+        let account = Account {
+            user: "alice".to_string(),
+            group: "alice".to_string(),
+            home: PathBuf::from("/nonexistent/home/alice"),
+        };
+        let config = config_path(None, Some(&account));
+        let units = render_units(
+            Path::new("/bin/duansheli"),
+            &config,
+            ServiceMode::Watch,
+            Some(&account),
+        );
+
+        assert!(
+            units[0].contents.contains(
+                "--config \"/nonexistent/home/alice/.config/duansheli/config.toml\" watch"
+            )
+        );
+        assert_eq!(
+            config_path(Some(Path::new("/etc/d.toml")), Some(&account)),
+            Path::new("/etc/d.toml")
+        );
+    }
+
+    #[test]
+    fn test_quote_escapes_specifiers() {
+        assert_eq!(quote("/a b/100%\"x\""), "\"/a b/100%%\\\"x\\\"\"");
+    }
+
+    #[test]
+    fn test_install_replaces_other_mode_and_uninstall_removes_all() {
+        let tmp = TempDir::new().unwrap();
+        let timer_units = render_units(
+            Path::new("/bin/duansheli"),
+            Path::new("/c.toml"),
+            ServiceMode::Timer {
+                every: Duration::from_secs(60),
+            },
+            None,
+        );
+        install(&timer_units, tmp.path()).unwrap();
+        assert!(tmp.path().join(TIMER_NAME).exists());
+
+        let daemon_units = render_units(
+            Path::new("/bin/duansheli"),
+            Path::new("/c.toml"),
+            ServiceMode::Daemon,
+            None,
+        );
+        install(&daemon_units, tmp.path()).unwrap();
+        assert!(
+            !tmp.path().join(TIMER_NAME).exists(),
+            "stale timer should be removed"
+        );
+        assert!(tmp.path().join(SERVICE_NAME).exists());
+
+        assert_eq!(
+            uninstall(tmp.path()).unwrap(),
+            vec![tmp.path().join(SERVICE_NAME)]
+        );
+        assert!(uninstall(tmp.path()).unwrap().is_empty());
+    }
+}
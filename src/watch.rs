@@ -0,0 +1,499 @@
+use crate::daemon::{self, DEFAULT_INTERVAL, RELOAD, TERMINATE};
+use crate::{ARCHIVE_DIR_NAME, DirConfig, Error, Result, RunOptions, declutter_directory_with};
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Quiet period after the last event before a directory is processed.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Longest wait for events, so signals are noticed promptly.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_MODIFY
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_ATTRIB
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// Modification times of the entries in a watched directory and its archive.
+#[derive(Debug, Default)]
+pub struct AgeIndex {
+    mtimes: HashMap<PathBuf, SystemTime>,
+}
+
+impl AgeIndex {
+    /// Index the direct children of `dirs`, skipping `exclude` names.
+    pub fn scan(dirs: &[&Path], exclude: &str) -> io::Result<Self> {
+        let mut index = AgeIndex::default();
+        for dir in dirs {
+            for entry in fs::read_dir(dir)?.filter_map(Result::ok) {
+                if entry.file_name() != exclude {
+                    index.update(&entry.path());
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Refresh one entry after an event, dropping it if it no longer exists.
+    pub fn update(&mut self, path: &Path) {
+        match path.symlink_metadata().and_then(|m| m.modified()) {
+            Ok(mtime) => {
+                self.mtimes.insert(path.to_path_buf(), mtime);
+            }
+            Err(_) => {
+                self.mtimes.remove(path);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mtimes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mtimes.is_empty()
+    }
+
+    /// The next instant after `now` at which any entry crosses one of
+    /// `thresholds` (ages in seconds).
+    pub fn next_crossing(&self, thresholds: &[u64], now: SystemTime) -> Option<SystemTime> {
+        self.mtimes
+            .values()
+            .flat_map(|mtime| {
+                thresholds
+                    .iter()
+                    .map(move |&t| *mtime + Duration::from_secs(t))
+            })
+            .filter(|crossing| *crossing > now)
+            .min()
+    }
+}
+
+/// Every entry age at which the planner's decision for `cfg` may change.
+pub fn age_thresholds(cfg: &DirConfig) -> Vec<u64> {
+    let mut thresholds = vec![
+        cfg.time_to_archive_hours * 3600,
+        cfg.time_to_deletion_hours * 3600,
+    ];
+    for rule in &cfg.rules {
+        thresholds.extend(rule.time_to_archive_hours.map(|h| h * 3600));
+        thresholds.extend(rule.time_to_deletion_hours.map(|h| h * 3600));
+        thresholds.extend(rule.min_age_hours.map(|h| h * 3600));
+        // ages are compared in whole hours, so `max_age_hours` stops matching an hour later
+        thresholds.extend(rule.max_age_hours.map(|h| (h + 1) * 3600));
+    }
+    thresholds.sort_unstable();
+    thresholds.dedup();
+    thresholds
+}
+
+#[derive(Debug, PartialEq)]
+struct Event {
+    wd: i32,
+    mask: u32,
+    name: Option<OsString>,
+}
+
+/// Minimal inotify wrapper.
+struct Inotify {
+    fd: OwnedFd,
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        // SAFETY: plain syscall; the returned descriptor is owned below.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a fresh descriptor that nothing else owns.
+        Ok(Inotify {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add_watch(&self, path: &Path) -> io::Result<i32> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `c_path` is NUL-terminated and outlives the call.
+        let wd =
+            unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Wait up to `timeout` for events to become readable.
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `pollfd` is a valid single-element array for the call.
+        let rc = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        match rc {
+            n if n > 0 => Ok(true),
+            0 => Ok(false),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    fn read_events(&self) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: `buf` is valid for writes of its full length.
+            let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(events);
+                }
+                return Err(err);
+            }
+            events.extend(parse_events(&buf[..n as usize]));
+        }
+    }
+}
+
+fn parse_events(mut buf: &[u8]) -> Vec<Event> {
+    const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+    let field = |b: &[u8], at: usize| <[u8; 4]>::try_from(&b[at..at + 4]).expect("4-byte field");
+
+    let mut events = Vec::new();
+    while buf.len() >= HEADER {
+        let wd = i32::from_ne_bytes(field(buf, 0));
+        let mask = u32::from_ne_bytes(field(buf, 4));
+        let len = u32::from_ne_bytes(field(buf, 12)) as usize;
+        let Some(raw_name) = buf.get(HEADER..HEADER + len) else {
+            break;
+        };
+        let name_len = raw_name.iter().position(|&b| b == 0).unwrap_or(len);
+        let name = (name_len > 0).then(|| OsString::from_vec(raw_name[..name_len].to_vec()));
+        events.push(Event { wd, mask, name });
+        buf = &buf[HEADER + len..];
+    }
+    events
+}
+
+struct Watched {
+    cfg: DirConfig,
+    archive_path: PathBuf,
+    wds: Vec<i32>,
+    /// Names of `move_to` destinations inside the directory.
+    sort_dests: Vec<glob::Pattern>,
+    index: AgeIndex,
+    thresholds: Vec<u64>,
+    rescan_interval: Duration,
+    next_rescan: Instant,
+    /// Time of the latest unprocessed event.
+    dirty_since: Option<Instant>,
+}
+
+impl Watched {
+    fn new(cfg: DirConfig, inotify: &Inotify) -> Result<Self> {
+        let archive_path = cfg.path.join(ARCHIVE_DIR_NAME);
+        fs::create_dir_all(&archive_path).map_err(Error::io("create archive", &archive_path))?;
+        let wds = vec![
+            inotify
+                .add_watch(&cfg.path)
+                .map_err(Error::io("watch", &cfg.path))?,
+            inotify
+                .add_watch(&archive_path)
+                .map_err(Error::io("watch", &archive_path))?,
+        ];
+        let thresholds = age_thresholds(&cfg);
+        let sort_dests = sort_destinations(&cfg);
+        let rescan_interval = cfg.interval.map_or(DEFAULT_INTERVAL, |i| i.0);
+
+        Ok(Watched {
+            cfg,
+            archive_path,
+            wds,
+            sort_dests,
+            index: AgeIndex::default(),
+            thresholds,
+            rescan_interval,
+            next_rescan: Instant::now(),
+            dirty_since: None,
+        })
+    }
+
+    /// When this directory next needs processing.
+    fn deadline(&self) -> Instant {
+        let now = Instant::now();
+        let crossing = self
+            .index
+            .next_crossing(&self.thresholds, SystemTime::now())
+            .and_then(|t| t.duration_since(SystemTime::now()).ok())
+            .map(|wait| now + wait);
+        let debounced = self.dirty_since.map(|t| t + DEBOUNCE);
+
+        [Some(self.next_rescan), crossing, debounced]
+            .into_iter()
+            .flatten()
+            .min()
+            .expect("rescan deadline is always set")
+    }
+
+    fn process(&mut self, opts: &RunOptions) {
+        log::info!("Processing directory: {}", self.cfg.path.display());
+        if let Err(e) = declutter_directory_with(self.cfg.clone(), opts) {
+            log::error!("{}: {e}", self.cfg.path.display());
+        }
+
+        let dirs = [self.cfg.path.as_path(), self.archive_path.as_path()];
+        match AgeIndex::scan(&dirs, ARCHIVE_DIR_NAME) {
+            Ok(index) => {
+                log::debug!(
+                    "Indexed {} entries in {}",
+                    index.len(),
+                    self.cfg.path.display()
+                );
+                self.index = index;
+            }
+            Err(e) => log::error!("Rescanning {}: {e}", self.cfg.path.display()),
+        }
+        self.dirty_since = None;
+        self.next_rescan = Instant::now() + self.rescan_interval;
+    }
+
+    fn handle(&mut self, event: &Event) {
+        if event.wd != self.wds[0] {
+            // Changes in the archive are mostly our own; they only move deletion cutoffs.
+            if let Some(name) = &event.name {
+                self.index.update(&self.archive_path.join(name));
+            }
+            return;
+        }
+        if let Some(name) = &event.name {
+            if name == ARCHIVE_DIR_NAME
+                || self
+                    .sort_dests
+                    .iter()
+                    .any(|p| p.matches(&name.to_string_lossy()))
+            {
+                return;
+            }
+            self.index.update(&self.cfg.path.join(name));
+        }
+        self.dirty_since = Some(Instant::now());
+    }
+}
+
+/// Name patterns of the `move_to` destinations that lie inside the directory,
+/// whose own changes should not trigger another run.
+fn sort_destinations(cfg: &DirConfig) -> Vec<glob::Pattern> {
+    cfg.rules
+        .iter()
+        .filter_map(|rule| rule.move_to.as_ref()?.child_of(&cfg.path))
+        .collect()
+}
+
+fn start_watching(dirs: Vec<DirConfig>) -> Result<(Inotify, Vec<Watched>)> {
+    let inotify = Inotify::new().map_err(Error::io("initialize inotify", ""))?;
+    let watched = dirs
+        .into_iter()
+        .map(|cfg| Watched::new(cfg, &inotify))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((inotify, watched))
+}
+
+/// Watch every directory with inotify and process each one as soon as an
+/// entry changes or crosses one of its age thresholds.
+///
+/// Each directory is also fully rescanned every `interval` (default 1h) to
+/// recover from missed events. Signals behave as in [`daemon::run`].
+pub fn run<F>(load_config: F, opts: &RunOptions) -> Result<()>
+where
+    F: Fn() -> Result<Vec<DirConfig>>,
+{
+    daemon::install_signal_handlers();
+    let opts = RunOptions {
+        cancel: Some(&TERMINATE),
+        ..opts.clone()
+    };
+
+    let (mut inotify, mut watched) = start_watching(load_config()?)?;
+    log::info!("Watching {} directories", watched.len());
+
+    while !TERMINATE.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            match load_config().and_then(start_watching) {
+                Ok((new_inotify, new_watched)) => {
+                    log::info!(
+                        "Reloaded config, watching {} directories",
+                        new_watched.len()
+                    );
+                    (inotify, watched) = (new_inotify, new_watched);
+                }
+                Err(e) => log::error!("Config reload failed, keeping previous config: {e}"),
+            }
+        }
+
+        let now = Instant::now();
+        for w in watched.iter_mut().filter(|w| w.deadline() <= now) {
+            if TERMINATE.load(Ordering::SeqCst) {
+                break;
+            }
+            w.process(&opts);
+        }
+
+        let next = watched.iter().map(Watched::deadline).min();
+        let timeout = next.map_or(POLL_INTERVAL, |d| {
+            d.saturating_duration_since(Instant::now())
+        });
+        if !inotify
+            .wait(timeout.min(POLL_INTERVAL))
+            .map_err(Error::io("wait for inotify events", ""))?
+        {
+            continue;
+        }
+
+        for event in inotify
+            .read_events()
+            .map_err(Error::io("read inotify events", ""))?
+        {
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                log::warn!("inotify queue overflowed, rescanning all directories");
+                watched
+                    .iter_mut()
+                    .for_each(|w| w.next_rescan = Instant::now());
+                continue;
+            }
+            if let Some(w) = watched.iter_mut().find(|w| w.wds.contains(&event.wd)) {
+                w.handle(&event);
+            }
+        }
+    }
+
+    log::info!("Watch stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rule;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_next_crossing_picks_earliest_future_threshold() {
+        let now = SystemTime::now();
+        let mut index = AgeIndex::default();
+        index
+            .mtimes
+            .insert(PathBuf::from("/a"), now - Duration::from_secs(100));
+        index
+            .mtimes
+            .insert(PathBuf::from("/b"), now - Duration::from_secs(3000));
+
+        let crossing = index.next_crossing(&[60, 3600], now).unwrap();
+        assert_eq!(crossing, now + Duration::from_secs(600));
+        assert_eq!(index.next_crossing(&[60], now), None);
+    }
+
+    #[test]
+    fn test_age_thresholds_include_rule_boundaries() {
+        let cfg = DirConfig {
+            time_to_archive_hours: 1,
+            time_to_deletion_hours: 24,
+            rules: vec![Rule {
+                min_age_hours: Some(2),
+                max_age_hours: Some(5),
+                time_to_archive_hours: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(age_thresholds(&cfg), vec![3600, 7200, 6 * 3600, 24 * 3600]);
+    }
+
+    #[test]
+    fn test_inotify_reports_created_files() {
+        let tmp = TempDir::new().unwrap();
+        let inotify = Inotify::new().unwrap();
+        let wd = inotify.add_watch(tmp.path()).unwrap();
+
+        fs::write(tmp.path().join("new.txt"), "x").unwrap();
+
+        assert!(inotify.wait(Duration::from_secs(1)).unwrap());
+        let events = inotify.read_events().unwrap();
+        assert!(events.iter().any(|e| {
+            e.wd == wd
+                && e.mask & libc::IN_CREATE != 0
+                && e.name.as_deref() == Some("new.txt".as_ref())
+        }));
+    }
+
+    #[test]
+    fn test_index_update_tracks_removal() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("f.txt");
+        fs::write(&path, "x").unwrap();
+
+        let mut index = AgeIndex::scan(&[tmp.path()], ARCHIVE_DIR_NAME).unwrap();
+        assert_eq!(index.len(), 1);
+
+        fs::remove_file(&path).unwrap();
+        index.update(&path);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_own_archive_and_sort_moves_do_not_trigger_a_run() {
+        let tmp = TempDir::new().unwrap();
+        let cfg = DirConfig {
+            path: tmp.path().to_path_buf(),
+            rules: vec![
+                toml::from_str(&format!(
+                    "move_to = \"{}/Sorted/{{ext}}\"",
+                    tmp.path().display()
+                ))
+                .unwrap(),
+            ],
+            ..Default::default()
+        };
+        let inotify = Inotify::new().unwrap();
+        let mut w = Watched::new(cfg, &inotify).unwrap();
+        let event = |wd, name: &str| Event {
+            wd,
+            mask: libc::IN_CREATE,
+            name: Some(name.into()),
+        };
+
+        fs::write(w.archive_path.join("a.bak"), "x").unwrap();
+        w.handle(&event(w.wds[1], "a.bak"));
+        w.handle(&event(w.wds[0], ARCHIVE_DIR_NAME));
+        w.handle(&event(w.wds[0], "Sorted"));
+        assert_eq!(w.dirty_since, None);
+        assert_eq!(
+            w.index.len(),
+            1,
+            "archived entries still count towards deletion cutoffs"
+        );
+
+        w.handle(&event(w.wds[0], "new.txt"));
+        assert!(w.dirty_since.is_some());
+    }
+}
@@ -4,6 +4,11 @@ use tempfile::TempDir;
 
 use duansheli::*;
 
+/// Lock files for every test go into Cargo's scratch directory rather than the real `$HOME`.
+fn state_dir() -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("state")
+}
+
 fn create_file_fixture(dir: &std::path::Path, name: &str, age_secs: u64) {
     create_fixture(dir, name, age_secs, false);
 }
@@ -90,23 +95,40 @@ fn test_directory_archival() {
     debug_print_tree_with_timestamps(root);
 
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: root.to_path_buf(),
         time_to_archive_hours,
         time_to_deletion_hours,
+        ..Default::default()
     };
 
     // act
     declutter_directory(cfg, false).unwrap();
 
     // assert — old entries moved to archive
-    assert!(!root.join("f_old.txt").exists(), "old file should be archived");
+    assert!(
+        !root.join("f_old.txt").exists(),
+        "old file should be archived"
+    );
     assert!(!root.join("D_OLD").exists(), "old dir should be archived");
-    assert!(!root.join("D_OLD_NESTING").exists(), "old nested dir should be archived");
-    assert!(!root.join("f_medium.txt").exists(), "medium file should be archived");
-    assert!(!root.join("D_MEDIUM").exists(), "medium dir should be archived");
+    assert!(
+        !root.join("D_OLD_NESTING").exists(),
+        "old nested dir should be archived"
+    );
+    assert!(
+        !root.join("f_medium.txt").exists(),
+        "medium file should be archived"
+    );
+    assert!(
+        !root.join("D_MEDIUM").exists(),
+        "medium dir should be archived"
+    );
 
     // young entries remain untouched
-    assert!(root.join("f_young.txt").exists(), "young file should remain");
+    assert!(
+        root.join("f_young.txt").exists(),
+        "young file should remain"
+    );
     assert!(root.join("D_YOUNG").exists(), "young dir should remain");
 
     // archive should contain all moved entries (with .bak suffix)
@@ -115,11 +137,31 @@ fn test_directory_archival() {
         .filter_map(|e| e.ok())
         .collect();
     assert_eq!(archived.len(), 5, "archive should contain 5 entries");
-    assert!(archived.iter().any(|e| e.file_name().to_string_lossy().starts_with("f_old.txt.")));
-    assert!(archived.iter().any(|e| e.file_name().to_string_lossy().starts_with("D_OLD.")));
-    assert!(archived.iter().any(|e| e.file_name().to_string_lossy().starts_with("D_OLD_NESTING.")));
-    assert!(archived.iter().any(|e| e.file_name().to_string_lossy().starts_with("f_medium.txt.")));
-    assert!(archived.iter().any(|e| e.file_name().to_string_lossy().starts_with("D_MEDIUM.")));
+    assert!(
+        archived
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("f_old.txt."))
+    );
+    assert!(
+        archived
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("D_OLD."))
+    );
+    assert!(archived.iter().any(|e| {
+        e.file_name()
+            .to_string_lossy()
+            .starts_with("D_OLD_NESTING.")
+    }));
+    assert!(
+        archived
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("f_medium.txt."))
+    );
+    assert!(
+        archived
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("D_MEDIUM."))
+    );
 }
 
 #[test]
@@ -133,25 +175,48 @@ fn test_directory_archival_dry_run() {
     let archive = root.join(".duansheli-archive");
 
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: root.to_path_buf(),
         time_to_archive_hours,
         time_to_deletion_hours,
+        ..Default::default()
     };
 
     declutter_directory(cfg, true).unwrap();
 
     // all entries should remain in place
-    assert!(root.join("f_old.txt").exists(), "old file should still exist");
+    assert!(
+        root.join("f_old.txt").exists(),
+        "old file should still exist"
+    );
     assert!(root.join("D_OLD").exists(), "old dir should still exist");
-    assert!(root.join("D_OLD_NESTING").exists(), "old nested dir should still exist");
-    assert!(root.join("f_medium.txt").exists(), "medium file should still exist");
-    assert!(root.join("D_MEDIUM").exists(), "medium dir should still exist");
-    assert!(root.join("f_young.txt").exists(), "young file should still exist");
-    assert!(root.join("D_YOUNG").exists(), "young dir should still exist");
+    assert!(
+        root.join("D_OLD_NESTING").exists(),
+        "old nested dir should still exist"
+    );
+    assert!(
+        root.join("f_medium.txt").exists(),
+        "medium file should still exist"
+    );
+    assert!(
+        root.join("D_MEDIUM").exists(),
+        "medium dir should still exist"
+    );
+    assert!(
+        root.join("f_young.txt").exists(),
+        "young file should still exist"
+    );
+    assert!(
+        root.join("D_YOUNG").exists(),
+        "young dir should still exist"
+    );
 
     // archive exists but should be empty
     assert!(archive.is_dir(), "archive directory should exist");
-    let archived: Vec<_> = fs::read_dir(&archive).unwrap().filter_map(|e| e.ok()).collect();
+    let archived: Vec<_> = fs::read_dir(&archive)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .collect();
     assert_eq!(archived.len(), 0, "archive should be empty in dry-run");
 }
 
@@ -166,34 +231,97 @@ fn test_permanent_deletion_dry_run() {
     let root = tmp_dir.path();
 
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: root.to_path_buf(),
         time_to_archive_hours,
         time_to_deletion_hours,
+        ..Default::default()
     };
 
     declutter_directory(cfg, true).unwrap();
 
     // all entries should remain in place
-    assert!(root.join("f_old.txt").exists(), "old file should still exist");
+    assert!(
+        root.join("f_old.txt").exists(),
+        "old file should still exist"
+    );
     assert!(root.join("D_OLD").exists(), "old dir should still exist");
-    assert!(root.join("D_OLD_NESTING").exists(), "old nested dir should still exist");
-    assert!(root.join("f_medium.txt").exists(), "medium file should still exist");
-    assert!(root.join("D_MEDIUM").exists(), "medium dir should still exist");
-    assert!(root.join("f_young.txt").exists(), "young file should still exist");
-    assert!(root.join("D_YOUNG").exists(), "young dir should still exist");
+    assert!(
+        root.join("D_OLD_NESTING").exists(),
+        "old nested dir should still exist"
+    );
+    assert!(
+        root.join("f_medium.txt").exists(),
+        "medium file should still exist"
+    );
+    assert!(
+        root.join("D_MEDIUM").exists(),
+        "medium dir should still exist"
+    );
+    assert!(
+        root.join("f_young.txt").exists(),
+        "young file should still exist"
+    );
+    assert!(
+        root.join("D_YOUNG").exists(),
+        "young dir should still exist"
+    );
 }
 
 #[test]
 fn test_declutter_rejects_dangerous_path() {
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: std::path::PathBuf::from("/"),
         time_to_archive_hours: 1,
         time_to_deletion_hours: 2,
+        ..Default::default()
     };
     let result = declutter_directory(cfg, true);
     assert!(result.is_err());
-    let err_msg = result.unwrap_err().to_string();
-    assert!(err_msg.contains("dangerous path"), "expected dangerous path error, got: {}", err_msg);
+    let err = result.unwrap_err();
+    assert!(
+        matches!(err, Error::DangerousPath { .. }),
+        "expected DangerousPath, got: {err:?}"
+    );
+    let err_msg = err.to_string();
+    assert!(
+        err_msg.contains("dangerous path"),
+        "expected dangerous path error, got: {}",
+        err_msg
+    );
+}
+
+#[test]
+fn test_missing_directory_is_named() {
+    let tmp_dir = TempDir::new().unwrap();
+    let missing = tmp_dir.path().join("gone");
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: missing.clone(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 2,
+        ..Default::default()
+    };
+
+    let err = declutter_directory(cfg, true).unwrap_err();
+
+    assert!(
+        matches!(&err, Error::MissingDirectory { path } if *path == missing),
+        "got: {err:?}"
+    );
+    assert!(err.to_string().contains(&*missing.to_string_lossy()));
+    assert!(!missing.exists(), "a missing directory must not be created");
+    assert!(matches!(
+        list_dir_with_meta(
+            &filesystem::RealFs,
+            &missing,
+            None,
+            SystemTime::now(),
+            SymlinkPolicy::default()
+        ),
+        Err(Error::MissingDirectory { .. })
+    ));
 }
 
 #[test]
@@ -212,19 +340,30 @@ fn test_ignored_files_survive_declutter() {
     create_file_fixture(root, "old_file.txt", exceeds_deletion_secs);
 
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: root.to_path_buf(),
         time_to_archive_hours,
         time_to_deletion_hours,
+        ..Default::default()
     };
 
     declutter_directory(cfg, false).unwrap();
 
     // Metadata files should survive
-    assert!(root.join(".DS_Store").exists(), ".DS_Store should be ignored and survive");
-    assert!(root.join("Thumbs.db").exists(), "Thumbs.db should be ignored and survive");
+    assert!(
+        root.join(".DS_Store").exists(),
+        ".DS_Store should be ignored and survive"
+    );
+    assert!(
+        root.join("Thumbs.db").exists(),
+        "Thumbs.db should be ignored and survive"
+    );
 
     // Normal old file should be gone (deleted, since it exceeds deletion threshold)
-    assert!(!root.join("old_file.txt").exists(), "old_file.txt should have been deleted");
+    assert!(
+        !root.join("old_file.txt").exists(),
+        "old_file.txt should have been deleted"
+    );
 
     // Metadata files should NOT be in the archive
     let archive = root.join(".duansheli-archive");
@@ -235,8 +374,14 @@ fn test_ignored_files_survive_declutter() {
             .collect();
         for entry in &archived {
             let name = entry.file_name().to_string_lossy().to_string();
-            assert!(!name.starts_with(".DS_Store"), ".DS_Store should not be in archive");
-            assert!(!name.starts_with("Thumbs.db"), "Thumbs.db should not be in archive");
+            assert!(
+                !name.starts_with(".DS_Store"),
+                ".DS_Store should not be in archive"
+            );
+            assert!(
+                !name.starts_with("Thumbs.db"),
+                "Thumbs.db should not be in archive"
+            );
         }
     }
 }
@@ -266,23 +411,40 @@ fn test_permanent_deletion() {
     debug_print_tree_with_timestamps(root);
 
     let cfg = DirConfig {
+        state_dir: Some(state_dir()),
         path: root.to_path_buf(),
         time_to_archive_hours,
         time_to_deletion_hours,
+        ..Default::default()
     };
 
     // act
     declutter_directory(cfg, false).unwrap();
 
     // assert — all old and medium entries removed from root
-    assert!(!root.join("f_old.txt").exists(), "old file should leave root");
+    assert!(
+        !root.join("f_old.txt").exists(),
+        "old file should leave root"
+    );
     assert!(!root.join("D_OLD").exists(), "old dir should leave root");
-    assert!(!root.join("D_OLD_NESTING").exists(), "old nested dir should leave root");
-    assert!(!root.join("f_medium.txt").exists(), "medium file should leave root");
-    assert!(!root.join("D_MEDIUM").exists(), "medium dir should leave root");
+    assert!(
+        !root.join("D_OLD_NESTING").exists(),
+        "old nested dir should leave root"
+    );
+    assert!(
+        !root.join("f_medium.txt").exists(),
+        "medium file should leave root"
+    );
+    assert!(
+        !root.join("D_MEDIUM").exists(),
+        "medium dir should leave root"
+    );
 
     // young entries untouched
-    assert!(root.join("f_young.txt").exists(), "young file should remain");
+    assert!(
+        root.join("f_young.txt").exists(),
+        "young file should remain"
+    );
     assert!(root.join("D_YOUNG").exists(), "young dir should remain");
 
     // archive: medium entries survive, old entries permanently deleted
@@ -297,6 +459,577 @@ fn test_permanent_deletion() {
         "only medium entries should survive in archive, but found: {:?}",
         remaining.iter().map(|e| e.file_name()).collect::<Vec<_>>()
     );
-    assert!(remaining.iter().any(|e| e.file_name().to_string_lossy().starts_with("f_medium.txt.")));
-    assert!(remaining.iter().any(|e| e.file_name().to_string_lossy().starts_with("D_MEDIUM.")));
-}
\ No newline at end of file
+    assert!(
+        remaining
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("f_medium.txt."))
+    );
+    assert!(
+        remaining
+            .iter()
+            .any(|e| e.file_name().to_string_lossy().starts_with("D_MEDIUM."))
+    );
+}
+#[test]
+fn test_free_space_watermark_reclaims_archive() {
+    let time_to_archive_hours: u64 = 1;
+    let time_to_deletion_hours: u64 = 999;
+    let exceeds_archive_secs = (time_to_archive_hours * 3600) + 1;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    let archive = root.join(".duansheli-archive");
+    fs::create_dir_all(&archive).unwrap();
+    create_file_fixture(&archive, "f_archived.txt.bak", exceeds_archive_secs);
+    create_dir_fixture(&archive, "D_ARCHIVED.bak", exceeds_archive_secs);
+    create_file_fixture(root, "f_young.txt", 0);
+
+    // an unreachable watermark forces every eligible archive entry out
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours,
+        time_to_deletion_hours,
+        min_free_space: Some("100%".parse().unwrap()),
+        pressure_min_age_hours: Some(1),
+        ..Default::default()
+    };
+
+    declutter_directory(cfg, false).unwrap();
+
+    let archived: Vec<_> = fs::read_dir(&archive)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .collect();
+    assert!(
+        archived.is_empty(),
+        "archive should be reclaimed under pressure"
+    );
+    assert!(
+        root.join("f_young.txt").exists(),
+        "young file is below the minimum age"
+    );
+}
+
+#[test]
+fn test_size_rule_archives_large_files_sooner() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+
+    // 2h old: past the large-file rule, but well below the directory threshold
+    let age_secs = 2 * 3600;
+    let large = root.join("large.iso");
+    fs::write(&large, vec![0u8; 4096]).unwrap();
+    let mtime = SystemTime::now() - Duration::from_secs(age_secs);
+    filetime::set_file_mtime(&large, filetime::FileTime::from_system_time(mtime)).unwrap();
+    create_file_fixture(root, "small.txt", age_secs);
+
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 24,
+        time_to_deletion_hours: 168,
+        rules: vec![rules::Rule {
+            min_size: Some(space::ByteSize(1024)),
+            time_to_archive_hours: Some(1),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(!large.exists(), "large file should be archived early");
+    assert!(
+        root.join("small.txt").exists(),
+        "small file follows the directory threshold"
+    );
+}
+
+#[test]
+fn test_rules_first_match_wins() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    let inbox = TempDir::new().unwrap();
+
+    let age_secs = 2 * 3600;
+    create_file_fixture(root, "notes.md", age_secs);
+    create_file_fixture(root, "movie.torrent", 0);
+    create_file_fixture(root, "paper.pdf", 0);
+    create_file_fixture(root, "other.txt", age_secs);
+
+    let cfg: DirConfig = toml::from_str(&format!(
+        r#"
+        path = "{}"
+        state_dir = "{}"
+        time_to_archive_hours = 1
+        time_to_deletion_hours = 999
+
+        [[rules]]
+        extension = "md"
+        action = "keep"
+
+        [[rules]]
+        glob = "*.torrent"
+        action = "delete"
+
+        [[rules]]
+        extension = "pdf"
+        move_to = "{}"
+        "#,
+        root.display(),
+        state_dir().display(),
+        inbox.path().display()
+    ))
+    .unwrap();
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(
+        root.join("notes.md").exists(),
+        "kept by rule despite its age"
+    );
+    assert!(
+        !root.join("movie.torrent").exists(),
+        "deleted by rule despite being young"
+    );
+    assert!(inbox.path().join("paper.pdf").exists(), "moved by rule");
+    assert!(
+        !root.join("other.txt").exists(),
+        "unmatched entry follows directory thresholds"
+    );
+}
+
+#[test]
+fn test_sort_into_templated_folders() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    let sorted = TempDir::new().unwrap();
+
+    create_file_fixture(root, "scan.PDF", 2 * 3600);
+    create_file_fixture(root, "photo.png", 0);
+
+    let cfg: DirConfig = toml::from_str(&format!(
+        r#"
+        path = "{}"
+        state_dir = "{}"
+        time_to_archive_hours = 999
+        time_to_deletion_hours = 999
+
+        [[rules]]
+        extension = ["pdf", "png"]
+        min_age_hours = 1
+        move_to = "{}/{{ext}}/{{year}}"
+        "#,
+        root.display(),
+        state_dir().display(),
+        sorted.path().display()
+    ))
+    .unwrap();
+
+    // an existing file with the same name must not be replaced
+    let year = chrono::Utc::now().format("%Y").to_string();
+    let dest = sorted.path().join("pdf").join(&year);
+    fs::create_dir_all(&dest).unwrap();
+    fs::write(dest.join("scan.PDF"), "existing").unwrap();
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(dest.join("scan.PDF")).unwrap(),
+        "existing"
+    );
+    assert!(
+        dest.join("scan (1).PDF").exists(),
+        "conflicting name gets a suffix"
+    );
+    assert!(root.join("photo.png").exists(), "too young for the rule");
+}
+
+#[test]
+fn test_kind_rule_matches_by_content() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+
+    fs::write(root.join("download (3)"), b"%PDF-1.7 fake document").unwrap();
+    fs::write(root.join("notes"), b"just text").unwrap();
+
+    let cfg: DirConfig = toml::from_str(&format!(
+        r#"
+        path = "{}"
+        state_dir = "{}"
+        time_to_archive_hours = 999
+        time_to_deletion_hours = 999
+
+        [[rules]]
+        kind = "pdf"
+        action = "archive"
+        "#,
+        root.display(),
+        state_dir().display()
+    ))
+    .unwrap();
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(
+        !root.join("download (3)").exists(),
+        "pdf content should be archived"
+    );
+    assert!(root.join("notes").exists(), "plain text should stay");
+}
+
+#[test]
+fn test_busy_files_are_skipped() {
+    let time_to_archive_hours: u64 = 1;
+    let exceeds_archive_secs = (time_to_archive_hours * 3600) + 1;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    create_file_fixture(root, "ubuntu.iso.part", exceeds_archive_secs);
+    create_file_fixture(root, "held.log", exceeds_archive_secs);
+    create_file_fixture(root, "idle.txt", exceeds_archive_secs);
+    create_dir_fixture(root, "D_HELD", exceeds_archive_secs);
+    let _held = fs::File::open(root.join("held.log")).unwrap();
+    let _held_inside = fs::File::open(root.join("D_HELD/f_child.txt")).unwrap();
+
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours,
+        time_to_deletion_hours: 999,
+        ..Default::default()
+    };
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(
+        root.join("ubuntu.iso.part").exists(),
+        "partial download should be skipped"
+    );
+    assert!(
+        root.join("held.log").exists(),
+        "open file should be skipped"
+    );
+    assert!(
+        root.join("D_HELD").exists(),
+        "directory holding an open file should be skipped"
+    );
+    assert!(
+        !root.join("idle.txt").exists(),
+        "idle file should be archived"
+    );
+}
+
+#[test]
+fn test_overlapping_run_is_refused() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    create_file_fixture(root, "f_old.txt", 2 * 3600);
+
+    let _held = lock::DirLock::acquire(&state_dir(), root, false).unwrap();
+
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 999,
+        ..Default::default()
+    };
+    let err = declutter_directory(cfg, false).unwrap_err();
+
+    assert!(
+        matches!(err, Error::Lock(_)),
+        "expected Error::Lock, got: {err:?}"
+    );
+    assert!(
+        err.to_string().contains("locked"),
+        "expected lock error, got: {}",
+        err
+    );
+    assert!(
+        root.join("f_old.txt").exists(),
+        "nothing should happen while locked"
+    );
+}
+
+#[test]
+fn test_followed_symlink_never_deletes_target() {
+    let outside = TempDir::new().unwrap();
+    create_dir_fixture(outside.path(), "D_TARGET", 3 * 3600);
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    std::os::unix::fs::symlink(outside.path().join("D_TARGET"), root.join("link")).unwrap();
+    std::os::unix::fs::symlink(root, root.join("loop")).unwrap();
+
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 2,
+        symlinks: SymlinkPolicy::Follow,
+        ..Default::default()
+    };
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(
+        root.join("link").symlink_metadata().is_err(),
+        "the old link should be removed"
+    );
+    assert!(
+        root.join("loop").symlink_metadata().is_ok(),
+        "the loop takes the root's age and stays"
+    );
+    assert!(
+        outside.path().join("D_TARGET/f_child.txt").exists(),
+        "the link target must survive"
+    );
+}
+
+#[test]
+fn test_protected_directories_are_left_alone() {
+    let tmp_dir = create_test_directory(3 * 3600, 2 * 3600, 60);
+    let root = tmp_dir.path();
+    fs::write(root.join("D_OLD").join(".duansheli-protect"), "").unwrap();
+    let old_mtime = SystemTime::now() - Duration::from_secs(3 * 3600);
+    filetime::set_file_mtime(
+        root.join("D_OLD"),
+        filetime::FileTime::from_system_time(old_mtime),
+    )
+    .unwrap();
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 2,
+        ..Default::default()
+    };
+
+    declutter_directory(cfg.clone(), false).unwrap();
+    assert!(
+        root.join("D_OLD/f_child.txt").exists(),
+        "a marked directory must survive"
+    );
+    assert!(!root.join("f_old.txt").exists());
+
+    fs::create_dir(root.join(".git")).unwrap();
+    let err = declutter_directory(cfg, false).unwrap_err();
+    assert!(
+        matches!(&err, Error::DangerousPath { reason, .. } if reason.contains(".git")),
+        "got: {err:?}"
+    );
+}
+
+#[test]
+fn test_run_limit_aborts_unless_forced() {
+    let tmp_dir = create_test_directory(3 * 3600, 2 * 3600, 60);
+    let root = tmp_dir.path();
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 2,
+        max_deletes_per_run: Some(1),
+        ..Default::default()
+    };
+
+    let err = declutter_directory(cfg.clone(), false).unwrap_err();
+    assert!(matches!(err, Error::LimitExceeded { .. }), "got: {err:?}");
+    assert!(err.to_string().contains("max_deletes_per_run (1)"), "{err}");
+    assert!(
+        root.join("f_old.txt").exists(),
+        "nothing is touched when a limit is exceeded"
+    );
+
+    let opts = RunOptions {
+        force: true,
+        ..Default::default()
+    };
+    declutter_directory_with(cfg, &opts).unwrap();
+    assert!(!root.join("f_old.txt").exists());
+}
+
+/// A version 2 git index listing `paths`, with zeroed stat data and object ids.
+fn git_index(paths: &[&str]) -> Vec<u8> {
+    let mut bytes = b"DIRC".to_vec();
+    bytes.extend(2u32.to_be_bytes());
+    bytes.extend((paths.len() as u32).to_be_bytes());
+    for path in paths {
+        let start = bytes.len();
+        bytes.extend([0; 60]);
+        bytes.extend((path.len() as u16).to_be_bytes());
+        bytes.extend(path.as_bytes());
+        let len = bytes.len() - start;
+        bytes.extend(vec![0; 8 - len % 8]);
+    }
+    bytes
+}
+
+#[test]
+fn test_git_aware_keeps_tracked_files_and_active_repositories() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path().join("inbox");
+    fs::create_dir_all(tmp_dir.path().join(".git")).unwrap();
+    fs::create_dir(&root).unwrap();
+    fs::write(
+        tmp_dir.path().join(".git/index"),
+        git_index(&["inbox/notes/todo.txt", "inbox/tracked.txt"]),
+    )
+    .unwrap();
+    create_file_fixture(&root, "tracked.txt", 3 * 3600);
+    create_file_fixture(&root, "untracked.txt", 3 * 3600);
+    create_dir_fixture(&root, "notes", 3 * 3600);
+
+    // A nested repository whose files are old but whose HEAD moved a minute ago.
+    create_dir_fixture(&root, "project", 3 * 3600);
+    fs::create_dir_all(root.join("project/.git/logs")).unwrap();
+    let committed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        - 60;
+    fs::write(
+        root.join("project/.git/logs/HEAD"),
+        format!(
+            "{0} {0} A U Thor <a@example.com> {committed} +0000\tcommit: work\n",
+            "0".repeat(40)
+        ),
+    )
+    .unwrap();
+    let old_mtime =
+        filetime::FileTime::from_system_time(SystemTime::now() - Duration::from_secs(3 * 3600));
+    filetime::set_file_mtime(root.join("project/f_child.txt"), old_mtime).unwrap();
+    filetime::set_file_mtime(root.join("project"), old_mtime).unwrap();
+
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.clone(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 10,
+        git_aware: true,
+        ..Default::default()
+    };
+    declutter_directory(cfg.clone(), false).unwrap();
+
+    assert!(
+        root.join("tracked.txt").exists(),
+        "tracked files are never archived"
+    );
+    assert!(
+        root.join("notes/f_child.txt").exists(),
+        "directories holding tracked files are kept"
+    );
+    assert!(
+        root.join("project/.git").exists(),
+        "a recently committed repository is young"
+    );
+    assert!(
+        !root.join("untracked.txt").exists(),
+        "untracked files age as usual"
+    );
+}
+
+#[test]
+fn test_dedupe_archives_identical_copies() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    for (name, contents, age_secs) in [
+        ("file.pdf", "same", 120),
+        ("file (1).pdf", "same", 60),
+        ("other.pdf", "diff", 60),
+    ] {
+        fs::write(root.join(name), contents).unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(age_secs);
+        filetime::set_file_mtime(root.join(name), filetime::FileTime::from_system_time(mtime))
+            .unwrap();
+    }
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 24,
+        time_to_deletion_hours: 48,
+        dedupe: true,
+        ..Default::default()
+    };
+
+    declutter_directory(cfg, false).unwrap();
+
+    assert!(root.join("file.pdf").exists(), "the oldest copy is kept");
+    assert!(root.join("other.pdf").exists());
+    assert!(!root.join("file (1).pdf").exists());
+    let archived: Vec<_> = fs::read_dir(root.join(ARCHIVE_DIR_NAME))
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(archived.len(), 1);
+    assert!(
+        archived[0].to_string_lossy().starts_with("file (1).pdf."),
+        "{archived:?}"
+    );
+}
+
+#[test]
+fn test_hardlinked_archive_shares_identical_content() {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path();
+    let archive = root.join(ARCHIVE_DIR_NAME);
+    let cfg = DirConfig {
+        state_dir: Some(state_dir()),
+        path: root.to_path_buf(),
+        time_to_archive_hours: 1,
+        time_to_deletion_hours: 10,
+        hardlink_archive: true,
+        ..Default::default()
+    };
+    // Archive the same download twice, one minute apart, so the archived names differ.
+    for minutes in [0, 1] {
+        fs::write(root.join("big.iso"), "image contents").unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(3 * 3600);
+        filetime::set_file_mtime(
+            root.join("big.iso"),
+            filetime::FileTime::from_system_time(mtime),
+        )
+        .unwrap();
+        let opts = RunOptions {
+            now: Some(chrono::Utc::now() + chrono::Duration::minutes(minutes)),
+            ..Default::default()
+        };
+        declutter_directory_with(cfg.clone(), &opts).unwrap();
+    }
+
+    let archived: Vec<_> = fs::read_dir(&archive)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| !p.ends_with(store::STORE_DIR_NAME))
+        .collect();
+    assert_eq!(archived.len(), 2, "{archived:?}");
+    assert!(
+        archived
+            .iter()
+            .all(|p| fs::metadata(p).unwrap().nlink() == 3)
+    );
+    let stats = store::archive_stats(&filesystem::RealFs, &archive).unwrap();
+    assert_eq!(
+        (stats.entries, stats.saved_bytes()),
+        (2, "image contents".len() as u64)
+    );
+
+    fs::remove_file(&archived[0]).unwrap();
+    declutter_directory(cfg.clone(), false).unwrap();
+    assert_eq!(
+        fs::metadata(&archived[1]).unwrap().nlink(),
+        2,
+        "the blob outlives all but the last link"
+    );
+
+    fs::remove_file(&archived[1]).unwrap();
+    declutter_directory(cfg, false).unwrap();
+    assert_eq!(
+        fs::read_dir(archive.join(store::STORE_DIR_NAME))
+            .unwrap()
+            .count(),
+        0
+    );
+}